@@ -0,0 +1,170 @@
+use std::{error::Error, fmt::Debug, future::Future, panic::AssertUnwindSafe, sync::Arc};
+
+use futures::future::BoxFuture;
+use futures_util::FutureExt;
+use mu_rust_message_common::{exchange::Exchange, topic::TopicPattern, TextMessage};
+use tokio::sync::{mpsc, Semaphore};
+
+use crate::{parse_var, to_lib_error, MessageClient, MessageClientError};
+
+pub const MSG_CONS_CONCURRENCY: &str = "MSG_CONS_CONCURRENCY";
+
+pub type HandlerResult = Result<(), Box<dyn Error + Send + Sync>>;
+
+/// Processes the exchanges of a topic pattern. Implemented by any async
+/// closure taking the exchange.
+pub trait Handler: Send + Sync + 'static {
+    fn call(&self, exchange: Exchange) -> BoxFuture<'static, HandlerResult>;
+}
+
+impl<F, Fut> Handler for F
+where
+    F: Fn(Exchange) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = HandlerResult> + Send + 'static,
+{
+    fn call(&self, exchange: Exchange) -> BoxFuture<'static, HandlerResult> {
+        Box::pin(self(exchange))
+    }
+}
+
+pub(crate) struct Route {
+    pattern: TopicPattern,
+    handler: Arc<dyn Handler>,
+}
+
+impl Debug for Route {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Route({})", self.pattern.as_str())
+    }
+}
+
+/// Resolves on ctrl-c, or on SIGTERM on unix.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let terminate = async {
+            match signal(SignalKind::terminate()) {
+                Ok(mut terminate) => {
+                    terminate.recv().await;
+                }
+                Err(e) => {
+                    tracing::error!("cannot listen to SIGTERM: {e}");
+                    std::future::pending::<()>().await;
+                }
+            }
+        };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate => {}
+        }
+    }
+    #[cfg(not(unix))]
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        tracing::error!("cannot listen to ctrl-c: {e}");
+        std::future::pending::<()>().await;
+    }
+}
+
+impl MessageClient {
+    /// Subscribe to the topic pattern and process its exchanges with the
+    /// handler once `run` is called. The first registered pattern matching
+    /// an exchange wins.
+    pub async fn on(
+        &mut self,
+        topic: &str,
+        handler: impl Handler,
+    ) -> Result<&mut Self, MessageClientError> {
+        let pattern = TopicPattern::parse(topic).map_err(to_lib_error)?;
+        self.subscribe(topic).await?;
+        self._routes.push(Route {
+            pattern,
+            handler: Arc::new(handler),
+        });
+        Ok(self)
+    }
+
+    /// Dispatch the incoming exchanges to their handler, at most
+    /// `MSG_CONS_CONCURRENCY` at once. Exchanges are acked when the handler
+    /// succeeds and nacked when it fails or panics, the broker dead-lettering
+    /// them after too many deliveries; those no handler matches are acked
+    /// with a warning. On SIGTERM (on unix) or ctrl-c, stop receiving
+    /// and wait for the running handlers before closing the connection.
+    pub async fn run(mut self) -> Result<(), MessageClientError> {
+        let concurrency = parse_var(MSG_CONS_CONCURRENCY, "8")? as usize;
+        let semaphore = Arc::new(Semaphore::new(concurrency));
+        let (done_sender, mut done) = mpsc::unbounded_channel::<(String, bool)>();
+        let shutdown = shutdown_signal();
+        tokio::pin!(shutdown);
+        let mut in_flight = 0usize;
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => break,
+                Some((id, success)) = done.recv() => {
+                    in_flight -= 1;
+                    self.settle(id, success).await?;
+                }
+                exchange = self.next_exchange(), if semaphore.available_permits() > 0 => {
+                    let permit = semaphore.clone().acquire_owned().await.map_err(to_lib_error)?;
+                    let exchange = match exchange {
+                        Ok(exchange) => exchange,
+                        Err(e) => {
+                            tracing::error!("{e}");
+                            continue;
+                        }
+                    };
+                    let Some(route) = self
+                        ._routes
+                        .iter()
+                        .find(|route| route.pattern.matches(&exchange.topic))
+                    else {
+                        // nobody would ever process it, no point in redelivering it
+                        tracing::warn!("no handler for {}, ack {}", exchange.topic, exchange.id());
+                        self.settle(exchange.delivery_id().to_owned(), true).await?;
+                        continue;
+                    };
+                    let handler = route.handler.clone();
+                    let done_sender = done_sender.clone();
+                    in_flight += 1;
+                    tokio::spawn(async move {
+                        let id = exchange.delivery_id().to_owned();
+                        let success = match AssertUnwindSafe(handler.call(exchange))
+                            .catch_unwind()
+                            .await
+                        {
+                            Ok(Ok(())) => true,
+                            Ok(Err(e)) => {
+                                tracing::error!("handler failed for {id}: {e}");
+                                false
+                            }
+                            Err(_) => {
+                                tracing::error!("handler panicked for {id}");
+                                false
+                            }
+                        };
+                        drop(permit);
+                        let _ = done_sender.send((id, success));
+                    });
+                }
+            }
+        }
+        tracing::info!("shutting down, waiting for {in_flight} handlers");
+        while in_flight > 0 {
+            let Some((id, success)) = done.recv().await else {
+                break;
+            };
+            in_flight -= 1;
+            self.settle(id, success).await?;
+        }
+        self.close().await
+    }
+
+    async fn settle(&mut self, id: String, success: bool) -> Result<(), MessageClientError> {
+        if success {
+            self.send_text(TextMessage::Ack(id)).await
+        } else {
+            self.send_text(TextMessage::Nack(id)).await
+        }
+    }
+}
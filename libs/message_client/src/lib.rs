@@ -1,11 +1,18 @@
-use std::time::Duration;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
 use std::{env::var, error::Error, fmt::Display};
 
+use futures_util::stream::{self, BoxStream};
 use futures_util::{SinkExt, StreamExt};
+pub use mu_rust_message_common::codec::Codec;
 pub use mu_rust_message_common::exchange::Exchange;
-pub use mu_rust_message_common::TextMessage;
+use mu_rust_message_common::topic::TopicPattern;
+pub use mu_rust_message_common::{
+    Backpressure, SlowConsumerPolicy, StartPosition, SubscribeOptions, SubscriptionInfo,
+    TextMessage,
+};
 use tokio::net::TcpStream;
-use tokio::sync::broadcast::{channel, Receiver, Sender};
+use tokio::sync::broadcast::{channel, error::RecvError, Receiver, Sender};
 use tokio::task::JoinHandle;
 use tokio_tungstenite::tungstenite::Message;
 use tokio_tungstenite::{
@@ -14,16 +21,49 @@ use tokio_tungstenite::{
     MaybeTlsStream, WebSocketStream,
 };
 
+use crate::consumer::Route;
+pub use crate::consumer::{Handler, HandlerResult, MSG_CONS_CONCURRENCY};
+
+mod consumer;
+
 pub const MSG_CONS_HOST: &str = "MSG_CONS_HOST";
 pub const MSG_CONS_PORT: &str = "MSG_CONS_PORT";
 pub const MSG_CONS_PROTOCOL: &str = "MSG_CONS_PROTOCOL";
 pub const MSG_CONS_TIMEOUT: &str = "MSG_CONS_TIMEOUT";
+pub const MSG_CONS_RECONNECT_MIN_DELAY: &str = "MSG_CONS_RECONNECT_MIN_DELAY";
+pub const MSG_CONS_RECONNECT_MAX_DELAY: &str = "MSG_CONS_RECONNECT_MAX_DELAY";
+pub const MSG_CONS_BUFFER_SIZE: &str = "MSG_CONS_BUFFER_SIZE";
+/// Bearer token sent to the broker, required when it authenticates connections.
+pub const MSG_CONS_TOKEN: &str = "MSG_CONS_TOKEN";
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Incoming exchanges. The stream does not end when idle nor when the
+/// connection is lost, the client reconnecting instead.
+pub type ExchangeStream<'a> = BoxStream<'a, Result<Exchange, MessageClientError>>;
 
+/// A websocket client that reconnects to the broker when the connection is
+/// lost, replaying its connect and subscribe messages. Exchanges sent while
+/// disconnected are buffered and flushed once reconnected.
 #[derive(Debug)]
 pub struct MessageClient {
     _agent: String,
-    _socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    _url: String,
+    _token: Option<Token>,
+    _socket: Option<Socket>,
     _timeout: Duration,
+    /// Subscribe messages replayed on each reconnect.
+    _subscriptions: Vec<TextMessage>,
+    /// Exchanges waiting for the connection to be back.
+    _outgoing: VecDeque<Message>,
+    _buffer_size: usize,
+    _backoff: Backoff,
+    /// Temporary reply queue, opened on the first request.
+    _reply_topic: Option<String>,
+    /// Exchanges received while waiting for a reply, returned first by `recv`.
+    _pending: VecDeque<Exchange>,
+    /// Handlers registered with `on`.
+    _routes: Vec<Route>,
 }
 #[derive(Debug)]
 pub struct MessageClientError {
@@ -39,106 +79,683 @@ pub fn to_lib_error(e: impl Error) -> MessageClientError {
     MessageClientError { msg: e.to_string() }
 }
 
+/// Bearer token, kept out of the debug output.
+struct Token(String);
+
+impl std::fmt::Debug for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Token(***)")
+    }
+}
+
+/// Exponential backoff between reconnect attempts.
+#[derive(Debug)]
+struct Backoff {
+    min: Duration,
+    max: Duration,
+    delay: Duration,
+    next_attempt: Instant,
+}
+
+impl Backoff {
+    fn new(min: Duration, max: Duration) -> Backoff {
+        Backoff {
+            min,
+            max,
+            delay: min,
+            next_attempt: Instant::now(),
+        }
+    }
+
+    fn failed(&mut self) {
+        self.next_attempt = Instant::now() + self.delay;
+        self.delay = (self.delay * 2).min(self.max);
+    }
+
+    fn reset(&mut self) {
+        self.delay = self.min;
+        self.next_attempt = Instant::now();
+    }
+}
+
+fn parse_var(key: &str, default: &str) -> Result<u64, MessageClientError> {
+    var(key)
+        .unwrap_or_else(|_| String::from(default))
+        .parse::<u64>()
+        .map_err(to_lib_error)
+}
+
+/// Whether the recorded message subscribes to the topic with the same tenant
+/// and group.
+fn is_subscription_of(message: &TextMessage, topic: &str, options: &SubscribeOptions) -> bool {
+    match message {
+        TextMessage::Subscribe(subscribed) => {
+            subscribed.eq_ignore_ascii_case(topic)
+                && options.tenant.is_none()
+                && options.group.is_none()
+        }
+        TextMessage::SubscribeWith {
+            topic: subscribed,
+            options: subscribed_options,
+        } => {
+            subscribed.eq_ignore_ascii_case(topic)
+                && subscribed_options.tenant == options.tenant
+                && subscribed_options.group == options.group
+        }
+        _ => false,
+    }
+}
+
+/// What the broker sends: exchanges, and control frames.
+enum Frame {
+    Exchange(Exchange),
+    Text(TextMessage),
+}
+
+async fn open_socket(
+    url: &str,
+    agent: &str,
+    token: Option<&str>,
+) -> Result<Socket, MessageClientError> {
+    let mut request = Request::builder();
+    if let Some(token) = token {
+        request = request.header("Authorization", format!("Bearer {token}"));
+    }
+    let request = request
+        .method("GET")
+        .header("Host", url)
+        .header("Connection", "Upgrade")
+        .header("Upgrade", "websocket")
+        .header("Sec-WebSocket-Version", "13")
+        .header(
+            "Sec-WebSocket-Key",
+            tungstenite::handshake::client::generate_key(),
+        )
+        .uri(url)
+        .body(())
+        .map_err(to_lib_error)?;
+    let (mut ws_stream, _) = connect_async(request).await.map_err(to_lib_error)?;
+    let connect = TextMessage::Connect(agent.into());
+
+    ws_stream
+        .send(tungstenite::Message::Text(
+            connect.serialize().map_err(to_lib_error)?,
+        ))
+        .await
+        .map_err(to_lib_error)?;
+    Ok(ws_stream)
+}
+
 impl MessageClient {
     pub async fn new(agent: &str) -> Result<MessageClient, MessageClientError> {
         let host = var(MSG_CONS_HOST).unwrap_or_else(|_| String::from("127.0.0.1"));
         let port = var(MSG_CONS_PORT).unwrap_or_else(|_| String::from("3000"));
         let protocol = var(MSG_CONS_PROTOCOL).unwrap_or_else(|_| String::from("ws"));
-        let timeout = var(MSG_CONS_TIMEOUT)
-            .unwrap_or_else(|_| String::from("1000"))
-            .parse::<u64>()
-            .map_err(to_lib_error)?;
+        let timeout = parse_var(MSG_CONS_TIMEOUT, "1000")?;
+        let min_delay = parse_var(MSG_CONS_RECONNECT_MIN_DELAY, "100")?;
+        let max_delay = parse_var(MSG_CONS_RECONNECT_MAX_DELAY, "30000")?;
+        let buffer_size = parse_var(MSG_CONS_BUFFER_SIZE, "1000")?;
+        let token = var(MSG_CONS_TOKEN).ok().map(Token);
         let url = format!("{protocol}://{host}:{port}");
-        let request = Request::builder()
-            .method("GET")
-            .header("Host", &url)
-            .header("Connection", "Upgrade")
-            .header("Upgrade", "websocket")
-            .header("Sec-WebSocket-Version", "13")
-            .header(
-                "Sec-WebSocket-Key",
-                tungstenite::handshake::client::generate_key(),
-            )
-            .uri(&url)
-            .body(())
-            .map_err(to_lib_error)?;
-        let (mut ws_stream, _) = connect_async(request).await.map_err(to_lib_error)?;
-        let connect = TextMessage::Connect(agent.into());
-
-        ws_stream
-            .send(tungstenite::Message::Text(
-                connect.serialize().map_err(to_lib_error)?,
-            ))
-            .await
-            .map_err(to_lib_error)?;
+        let ws_stream = open_socket(&url, agent, token.as_ref().map(|t| t.0.as_str())).await?;
         Ok(MessageClient {
             _agent: agent.into(),
-            _socket: ws_stream,
+            _url: url,
+            _token: token,
+            _socket: Some(ws_stream),
             _timeout: Duration::from_millis(timeout),
+            _subscriptions: vec![],
+            _outgoing: VecDeque::new(),
+            _buffer_size: buffer_size as usize,
+            _backoff: Backoff::new(
+                Duration::from_millis(min_delay),
+                Duration::from_millis(max_delay),
+            ),
+            _reply_topic: None,
+            _pending: VecDeque::new(),
+            _routes: vec![],
         })
     }
+    pub fn is_connected(&self) -> bool {
+        self._socket.is_some()
+    }
 
     pub async fn subscribe(&mut self, topic: &str) -> Result<(), MessageClientError> {
-        let subscribe = TextMessage::Subscribe(topic.into());
-        self._socket
-            .send(tungstenite::Message::Text(
-                subscribe.serialize().map_err(to_lib_error)?,
-            ))
+        self.send_text(TextMessage::Subscribe(topic.into())).await
+    }
+
+    pub async fn subscribe_with(
+        &mut self,
+        topic: &str,
+        options: SubscribeOptions,
+    ) -> Result<(), MessageClientError> {
+        self.send_text(TextMessage::SubscribeWith {
+            topic: topic.into(),
+            options,
+        })
+        .await
+    }
+
+    pub async fn subscribe_group(
+        &mut self,
+        topic: &str,
+        group: &str,
+    ) -> Result<(), MessageClientError> {
+        let options = SubscribeOptions {
+            group: Some(group.into()),
+            ..Default::default()
+        };
+        self.subscribe_with(topic, options).await
+    }
+
+    /// Subscribe and replay the retained exchanges from that position, then
+    /// keep receiving the live ones.
+    pub async fn subscribe_from(
+        &mut self,
+        topic: &str,
+        from: StartPosition,
+    ) -> Result<(), MessageClientError> {
+        let options = SubscribeOptions {
+            from: Some(from),
+            ..Default::default()
+        };
+        self.subscribe_with(topic, options).await
+    }
+
+    /// Stop receiving the exchanges of a subscription made with the same
+    /// topic, tenant and group.
+    pub async fn unsubscribe(
+        &mut self,
+        topic: &str,
+        options: SubscribeOptions,
+    ) -> Result<(), MessageClientError> {
+        self.send_text(TextMessage::Unsubscribe {
+            topic: topic.into(),
+            options,
+        })
+        .await
+    }
+
+    /// Ask the broker for the subscriptions of this client, the exchanges
+    /// received in the meantime staying available through `recv`.
+    pub async fn list_subscriptions(
+        &mut self,
+    ) -> Result<Vec<SubscriptionInfo>, MessageClientError> {
+        self.send_text(TextMessage::ListSubscriptions).await?;
+        let timeout = self._timeout;
+        let wait_list = async {
+            loop {
+                self.check_room()?;
+                match self.read_frame().await? {
+                    Frame::Exchange(exchange) => self.hold(exchange),
+                    Frame::Text(TextMessage::Subscriptions(subscriptions)) => {
+                        return Ok(subscriptions)
+                    }
+                    Frame::Text(message) => Self::check_text(message)?,
+                }
+            }
+        };
+        tokio::time::timeout(timeout, wait_list)
             .await
-            .map_err(to_lib_error)?;
+            .map_err(|_| MessageClientError {
+                msg: format!("no subscription list within {timeout:?}"),
+            })?
+    }
+
+    pub async fn ack(&mut self, exchange: &Exchange) -> Result<(), MessageClientError> {
+        self.send_text(TextMessage::Ack(exchange.delivery_id().to_owned()))
+            .await
+    }
+
+    pub async fn nack(&mut self, exchange: &Exchange) -> Result<(), MessageClientError> {
+        self.send_text(TextMessage::Nack(exchange.delivery_id().to_owned()))
+            .await
+    }
+
+    /// Ask the broker to send the dead letters, received through `recv` like other exchanges.
+    pub async fn inspect_dead_letters(&mut self) -> Result<(), MessageClientError> {
+        self.send_text(TextMessage::InspectDeadLetters).await
+    }
+
+    pub async fn replay_dead_letters(&mut self) -> Result<(), MessageClientError> {
+        self.send_text(TextMessage::ReplayDeadLetters).await
+    }
+
+    /// Publish a request and wait for its reply, other exchanges received in
+    /// the meantime staying available through `recv`.
+    pub async fn request(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        timeout: Duration,
+    ) -> Result<Exchange, MessageClientError> {
+        let reply_topic = match &self._reply_topic {
+            Some(reply_topic) => reply_topic.clone(),
+            None => {
+                let reply_topic = Exchange::reply_topic();
+                self.send_text(TextMessage::ReplyQueue(reply_topic.clone()))
+                    .await?;
+                self._reply_topic = Some(reply_topic.clone());
+                reply_topic
+            }
+        };
+        let request =
+            Exchange::new(payload, topic, None, HashMap::new()).with_reply_to(&reply_topic);
+        let request_id = request.id().to_owned();
+        self.check_room()?;
+        self.send(request).await?;
+        let wait_reply = async {
+            loop {
+                self.check_room()?;
+                let exchange = self.read_exchange().await?;
+                if exchange.correlation_id() == Some(request_id.as_str()) {
+                    return Ok(exchange);
+                }
+                self.hold(exchange);
+            }
+        };
+        tokio::time::timeout(timeout, wait_reply)
+            .await
+            .map_err(|_| MessageClientError {
+                msg: format!("no reply to {request_id} within {timeout:?}"),
+            })?
+    }
+
+    /// Answer a request received through `recv`.
+    pub async fn reply(
+        &mut self,
+        request: &Exchange,
+        payload: &[u8],
+    ) -> Result<(), MessageClientError> {
+        let reply = request
+            .reply(payload, HashMap::new())
+            .ok_or_else(|| MessageClientError {
+                msg: format!("exchange {} does not expect a reply", request.id()),
+            })?;
+        self.send(reply).await
+    }
+
+    /// Send a control message. Subscriptions are recorded to be replayed on
+    /// reconnect; other messages are dropped while disconnected.
+    async fn send_text(&mut self, message: TextMessage) -> Result<(), MessageClientError> {
+        let text = message.serialize().map_err(to_lib_error)?;
+        let replayed = match message {
+            // a replay only restarts from its position once
+            TextMessage::SubscribeWith { topic, options } => Some(TextMessage::SubscribeWith {
+                topic,
+                options: SubscribeOptions {
+                    from: None,
+                    ..options
+                },
+            }),
+            message @ (TextMessage::Subscribe(_) | TextMessage::ReplyQueue(_)) => Some(message),
+            TextMessage::Unsubscribe { topic, options } => {
+                self._subscriptions
+                    .retain(|s| !is_subscription_of(s, &topic, &options));
+                None
+            }
+            _ => None,
+        };
+        if let Some(message) = replayed {
+            // subscribing again replaces the options of the subscription
+            match &message {
+                TextMessage::Subscribe(topic) => self
+                    ._subscriptions
+                    .retain(|s| !is_subscription_of(s, topic, &Default::default())),
+                TextMessage::SubscribeWith { topic, options } => self
+                    ._subscriptions
+                    .retain(|s| !is_subscription_of(s, topic, options)),
+                _ => {}
+            }
+            if !self._subscriptions.contains(&message) {
+                self._subscriptions.push(message);
+            }
+        }
+        let Some(socket) = &mut self._socket else {
+            tracing::debug!("disconnected, {text} not sent");
+            self.reconnect(false).await;
+            return Ok(());
+        };
+        if let Err(e) = socket.send(Message::Text(text)).await {
+            tracing::warn!("connection lost: {e}");
+            self._socket = None;
+        }
         Ok(())
     }
-    pub async fn recv(&mut self) -> Option<Result<Exchange, MessageClientError>> {
-        tracing::trace!("receiving...");
 
-        if let Ok(Some(msg)) = tokio::time::timeout(self._timeout, self._socket.next()).await {
-            match msg {
-                Ok(tungstenite::Message::Binary(binary)) => {
-                    Some(Exchange::deserialize(&binary).map_err(to_lib_error))
+    /// Open a new connection and replay the subscriptions, then flush the
+    /// outgoing buffer. Unless `wait` is set, nothing is attempted before the
+    /// backoff delay expired. Returns whether the client is connected.
+    async fn reconnect(&mut self, wait: bool) -> bool {
+        while self._socket.is_none() {
+            let now = Instant::now();
+            if now < self._backoff.next_attempt {
+                if !wait {
+                    return false;
                 }
-                message => {
-                    tracing::error!("socket sent an invalid message {message:?}");
-                    None
+                tokio::time::sleep(self._backoff.next_attempt - now).await;
+            }
+            match self.replay_subscriptions().await {
+                Ok(socket) => {
+                    tracing::info!("reconnected to {}", self._url);
+                    self._backoff.reset();
+                    self._socket = Some(socket);
+                }
+                Err(e) => {
+                    tracing::warn!("could not reconnect to {}: {e}", self._url);
+                    self._backoff.failed();
+                    if !wait {
+                        return false;
+                    }
                 }
             }
-        } else {
-            None
         }
+        self.flush().await;
+        self._socket.is_some()
+    }
+
+    async fn replay_subscriptions(&self) -> Result<Socket, MessageClientError> {
+        let token = self._token.as_ref().map(|t| t.0.as_str());
+        let mut socket = open_socket(&self._url, &self._agent, token).await?;
+        for subscription in self._subscriptions.iter() {
+            socket
+                .send(Message::Text(
+                    subscription.serialize().map_err(to_lib_error)?,
+                ))
+                .await
+                .map_err(to_lib_error)?;
+        }
+        Ok(socket)
+    }
+
+    /// Send the buffered exchanges in order, keeping them on failure.
+    async fn flush(&mut self) {
+        while let Some(message) = self._outgoing.front() {
+            let Some(socket) = &mut self._socket else {
+                return;
+            };
+            if let Err(e) = socket.send(message.clone()).await {
+                tracing::warn!("connection lost: {e}");
+                self._socket = None;
+                return;
+            }
+            self._outgoing.pop_front();
+        }
+    }
+
+    /// Next exchange or control frame from the broker, reconnecting as long
+    /// as it takes.
+    async fn read_frame(&mut self) -> Result<Frame, MessageClientError> {
+        loop {
+            self.reconnect(true).await;
+            let Some(socket) = &mut self._socket else {
+                continue;
+            };
+            match socket.next().await {
+                Some(Ok(Message::Binary(binary))) => {
+                    return Exchange::deserialize(&binary)
+                        .map(Frame::Exchange)
+                        .map_err(to_lib_error)
+                }
+                Some(Ok(Message::Ping(_) | Message::Pong(_))) => {}
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                    tracing::warn!("connection to {} lost", self._url);
+                    self._socket = None;
+                }
+                Some(Ok(Message::Text(text))) => {
+                    return TextMessage::deserialize(&text)
+                        .map(Frame::Text)
+                        .map_err(|_| MessageClientError {
+                            msg: format!("socket sent an invalid message {text}"),
+                        })
+                }
+                Some(Ok(message)) => {
+                    return Err(MessageClientError {
+                        msg: format!("socket sent an invalid message {message:?}"),
+                    })
+                }
+            }
+        }
+    }
+
+    /// Confirmations are only logged, errors are returned.
+    fn check_text(message: TextMessage) -> Result<(), MessageClientError> {
+        match message {
+            TextMessage::Error(error) => Err(MessageClientError {
+                msg: format!("broker error: {error}"),
+            }),
+            TextMessage::Subscribed(subscription) => {
+                tracing::debug!("subscribed to {subscription:?}");
+                Ok(())
+            }
+            TextMessage::Unsubscribed(subscription) => {
+                tracing::debug!("unsubscribed from {subscription:?}");
+                Ok(())
+            }
+            TextMessage::Subscriptions(_) => Ok(()),
+            message => Err(MessageClientError {
+                msg: format!("socket sent an invalid message {message:?}"),
+            }),
+        }
+    }
+
+    /// Next exchange from the broker, reconnecting as long as it takes.
+    async fn read_exchange(&mut self) -> Result<Exchange, MessageClientError> {
+        loop {
+            match self.read_frame().await? {
+                Frame::Exchange(exchange) => return Ok(exchange),
+                Frame::Text(message) => Self::check_text(message)?,
+            }
+        }
+    }
+
+    /// Keep an exchange received while waiting for another one. None is
+    /// dropped: see `check_room`.
+    fn hold(&mut self, exchange: Exchange) {
+        self._pending.push_back(exchange);
+    }
+
+    /// Backpressure on the exchanges held back: once `MSG_CONS_BUFFER_SIZE`
+    /// of them are, the socket is no longer read, which in turn holds back
+    /// the broker, and waiting for a reply or a streamed exchange fails until
+    /// `recv` takes them.
+    fn check_room(&self) -> Result<(), MessageClientError> {
+        if self._pending.len() >= self._buffer_size {
+            return Err(MessageClientError {
+                msg: format!(
+                    "{} exchanges held back, receive them first",
+                    self._pending.len()
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    /// The exchanges received while waiting for a reply come first.
+    async fn next_exchange(&mut self) -> Result<Exchange, MessageClientError> {
+        match self._pending.pop_front() {
+            Some(exchange) => Ok(exchange),
+            None => self.read_exchange().await,
+        }
+    }
+
+    /// Exchanges of every subscription, in order of arrival.
+    pub fn stream(&mut self) -> ExchangeStream<'_> {
+        stream::unfold(self, |client| async move {
+            let exchange = client.next_exchange().await;
+            Some((exchange, client))
+        })
+        .boxed()
+    }
+
+    /// Subscribe to the topic and stream the exchanges matching it. The
+    /// exchanges of other subscriptions stay available through `recv`; once
+    /// `MSG_CONS_BUFFER_SIZE` are held back, the stream yields an error
+    /// until they are received.
+    pub async fn subscribe_stream(
+        &mut self,
+        topic: &str,
+    ) -> Result<ExchangeStream<'_>, MessageClientError> {
+        let pattern = TopicPattern::parse(topic).map_err(to_lib_error)?;
+        self.subscribe(topic).await?;
+        Ok(
+            stream::unfold((self, pattern), |(client, pattern)| async move {
+                if let Some(position) = client
+                    ._pending
+                    .iter()
+                    .position(|e| pattern.matches(&e.topic))
+                {
+                    let exchange = client._pending.remove(position).map(Ok)?;
+                    return Some((exchange, (client, pattern)));
+                }
+                loop {
+                    if let Err(e) = client.check_room() {
+                        return Some((Err(e), (client, pattern)));
+                    }
+                    match client.read_exchange().await {
+                        Ok(exchange) if !pattern.matches(&exchange.topic) => client.hold(exchange),
+                        exchange => return Some((exchange, (client, pattern))),
+                    }
+                }
+            })
+            .boxed(),
+        )
+    }
+
+    /// Next exchange, `None` only if nothing arrived within the client
+    /// timeout: a lost connection is re-established in the meantime.
+    pub async fn recv(&mut self) -> Option<Result<Exchange, MessageClientError>> {
+        tracing::trace!("receiving...");
+        tokio::time::timeout(self._timeout, self.next_exchange())
+            .await
+            .ok()
     }
 
+    /// Send the exchange, or buffer it until the connection is back.
     pub async fn send(&mut self, message: Exchange) -> Result<(), MessageClientError> {
         let msg = message.serialize().map_err(to_lib_error)?;
-        self._socket
-            .send(Message::Binary(msg))
-            .await
-            .map_err(to_lib_error)?;
+        if self._outgoing.len() >= self._buffer_size {
+            return Err(MessageClientError {
+                msg: format!("disconnected and {} exchanges buffered", self._buffer_size),
+            });
+        }
+        self._outgoing.push_back(Message::Binary(msg));
+        if self._socket.is_some() {
+            self.flush().await;
+        } else {
+            self.reconnect(false).await;
+        }
         Ok(())
     }
 
+    /// Send the exchanges of the returned channel from a task. The task
+    /// closes the client once every sender is dropped and the pending
+    /// exchanges are sent: await the handle to shut down gracefully.
     pub fn spawn_send(mut self) -> (Sender<Exchange>, JoinHandle<()>) {
         let (sender, mut receiver): (Sender<Exchange>, Receiver<Exchange>) = channel(16);
 
         let task = tokio::spawn(async move {
             loop {
-                if let Ok(msg) = receiver.recv().await {
-                    tracing::debug!("receiving {msg:?}");
-                    if let Err(e) = self.send(msg).await {
-                        tracing::error!("error sending msg {e}");
+                match receiver.recv().await {
+                    Ok(msg) => {
+                        tracing::debug!("receiving {msg:?}");
+                        if let Err(e) = self.send(msg).await {
+                            tracing::error!("error sending msg {e}");
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::error!("{skipped} exchanges were not sent")
                     }
+                    Err(RecvError::Closed) => break,
                 }
             }
+            if let Err(e) = self.close().await {
+                tracing::error!("error closing client {e}");
+            }
         });
         (sender, task)
     }
+
+    /// Flush the buffered exchanges, then close the connection and wait for
+    /// the broker to acknowledge it, at most the client timeout.
+    pub async fn close(mut self) -> Result<(), MessageClientError> {
+        self.flush().await;
+        if !self._outgoing.is_empty() {
+            tracing::warn!("closing with {} exchanges not sent", self._outgoing.len());
+        }
+        let Some(mut socket) = self._socket.take() else {
+            return Ok(());
+        };
+        socket
+            .send(Message::Close(None))
+            .await
+            .map_err(to_lib_error)?;
+        let closed = async {
+            while let Some(Ok(message)) = socket.next().await {
+                if let Message::Close(_) = message {
+                    break;
+                }
+            }
+        };
+        if tokio::time::timeout(self._timeout, closed).await.is_err() {
+            tracing::warn!(
+                "broker did not acknowledge the close within {:?}",
+                self._timeout
+            );
+        }
+        Ok(())
+    }
 }
 
 impl Drop for MessageClient {
     fn drop(&mut self) {
-        futures::executor::block_on(async move {
-            self._socket.close(None).await.unwrap();
-        })
+        if self._socket.is_some() {
+            tracing::debug!(
+                "{} dropped without close, the connection is reset",
+                self._agent
+            );
+        }
     }
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{is_subscription_of, Backoff, SubscribeOptions, TextMessage};
+
+    #[test]
+    fn match_subscriptions() {
+        let workers = SubscribeOptions {
+            group: Some("workers".into()),
+            ..Default::default()
+        };
+        let subscribe = TextMessage::Subscribe("animal".into());
+        assert!(is_subscription_of(
+            &subscribe,
+            "Animal",
+            &Default::default()
+        ));
+        assert!(!is_subscription_of(&subscribe, "Animal", &workers));
+        let subscribe_group = TextMessage::SubscribeWith {
+            topic: "animal".into(),
+            options: workers.clone(),
+        };
+        assert!(is_subscription_of(&subscribe_group, "animal", &workers));
+        assert!(!is_subscription_of(&subscribe_group, "person", &workers));
+    }
+
+    #[test]
+    fn exponential_backoff() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_millis(300));
+        assert!(backoff.next_attempt <= Instant::now());
+        backoff.failed();
+        assert_eq!(Duration::from_millis(200), backoff.delay);
+        backoff.failed();
+        backoff.failed();
+        assert_eq!(Duration::from_millis(300), backoff.delay);
+        assert!(backoff.next_attempt > Instant::now());
+        backoff.reset();
+        assert_eq!(Duration::from_millis(100), backoff.delay);
+    }
+}
@@ -4,7 +4,7 @@ mod test {
     use std::collections::HashMap;
 
     use mu_rust_message_client::MessageClient;
-    use mu_rust_message_common::exchange::Exchange;
+    use mu_rust_message_common::{exchange::Exchange, SubscribeOptions};
     use tracing::Level;
     use tracing_subscriber::FmtSubscriber;
 
@@ -32,18 +32,28 @@ mod test {
                     ))
                     .await
                     .unwrap();
-                drop(client);
+                client.close().await.unwrap();
             }));
         }
         futures_util::future::join_all(fut).await;
 
         let mut client = MessageClient::new("person_sub").await.unwrap();
-        client.subscribe("Animal").await.unwrap();
+        client
+            .subscribe_with(
+                "Animal",
+                SubscribeOptions {
+                    tenant: Some("artcoded".into()),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
         let mut count = 0;
-        while let Some(Ok(msg)) = client.recv().await {
-            tracing::info!("{msg:?}");
-            let msg = Exchange::get_message_as_string(&msg.message);
+        while let Some(Ok(msg_ex)) = client.recv().await {
+            tracing::info!("{msg_ex:?}");
+            let msg = Exchange::get_message_as_string(&msg_ex.message);
             assert_eq!("Hello World", &msg);
+            client.ack(&msg_ex).await.unwrap();
             count += 1;
         }
 
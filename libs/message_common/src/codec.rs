@@ -0,0 +1,107 @@
+use std::{error::Error, fmt::Display};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Header recording the codec of the exchange message.
+pub const HEADER_CONTENT_TYPE: &str = "content-type";
+
+/// Formats a typed payload can be encoded with, identified on the wire by
+/// their content type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Json,
+    Bincode,
+    MessagePack,
+    Cbor,
+}
+
+#[derive(Debug)]
+pub struct CodecError {
+    pub msg: String,
+}
+
+impl Display for CodecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl Error for CodecError {}
+
+fn to_codec_error(e: impl Display) -> CodecError {
+    CodecError { msg: e.to_string() }
+}
+
+impl Codec {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Codec::Json => "application/json",
+            Codec::Bincode => "application/x-bincode",
+            Codec::MessagePack => "application/msgpack",
+            Codec::Cbor => "application/cbor",
+        }
+    }
+
+    /// The codec of a content type, ignoring its parameters.
+    pub fn from_content_type(content_type: &str) -> Option<Codec> {
+        let mime = content_type.split(';').next()?.trim().to_lowercase();
+        [Codec::Json, Codec::Bincode, Codec::MessagePack, Codec::Cbor]
+            .into_iter()
+            .find(|codec| codec.content_type() == mime)
+    }
+
+    pub fn encode<T: Serialize>(&self, payload: &T) -> Result<Vec<u8>, CodecError> {
+        match self {
+            Codec::Json => serde_json::to_vec(payload).map_err(to_codec_error),
+            Codec::Bincode => bincode::serialize(payload).map_err(to_codec_error),
+            Codec::MessagePack => rmp_serde::to_vec_named(payload).map_err(to_codec_error),
+            Codec::Cbor => {
+                let mut buffer = vec![];
+                ciborium::ser::into_writer(payload, &mut buffer).map_err(to_codec_error)?;
+                Ok(buffer)
+            }
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, message: &[u8]) -> Result<T, CodecError> {
+        match self {
+            Codec::Json => serde_json::from_slice(message).map_err(to_codec_error),
+            Codec::Bincode => bincode::deserialize(message).map_err(to_codec_error),
+            Codec::MessagePack => rmp_serde::from_slice(message).map_err(to_codec_error),
+            Codec::Cbor => ciborium::de::from_reader(message).map_err(to_codec_error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde::{Deserialize, Serialize};
+
+    use super::Codec;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Animal {
+        name: String,
+        legs: u8,
+        tags: Vec<String>,
+    }
+
+    #[test]
+    fn round_trip_codecs() {
+        let animal = Animal {
+            name: "Cat".into(),
+            legs: 4,
+            tags: vec!["pet".into()],
+        };
+        for codec in [Codec::Json, Codec::Bincode, Codec::MessagePack, Codec::Cbor] {
+            let encoded = codec.encode(&animal).unwrap();
+            assert_eq!(animal, codec.decode::<Animal>(&encoded).unwrap());
+            assert_eq!(Some(codec), Codec::from_content_type(codec.content_type()));
+        }
+        assert_eq!(
+            Some(Codec::Json),
+            Codec::from_content_type("Application/JSON; charset=utf-8")
+        );
+        assert_eq!(None, Codec::from_content_type("text/plain"));
+    }
+}
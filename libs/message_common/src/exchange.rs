@@ -1,7 +1,33 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Duration};
 
-use chrono::{Local, NaiveDateTime};
-use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Local, NaiveDateTime};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::codec::{Codec, CodecError, HEADER_CONTENT_TYPE};
+
+/// Unique id of the exchange. The broker assigns one to exchanges published
+/// without it.
+pub const HEADER_MESSAGE_ID: &str = "x-message-id";
+/// Set by the broker on each exchange it delivers: the log and offset it was
+/// read from, which consumers acknowledge. Unlike the message id, publishers
+/// cannot choose it.
+pub const HEADER_DELIVERY_ID: &str = "x-delivery-id";
+/// Why the broker moved an exchange to the dead-letter queue.
+pub const HEADER_DEAD_LETTER_REASON: &str = "x-dead-letter-reason";
+/// Consumer that failed to process a dead-lettered exchange.
+pub const HEADER_DEAD_LETTER_CONSUMER: &str = "x-dead-letter-consumer";
+/// Set on dead letters whose payload could not be decoded as an exchange.
+pub const HEADER_DEAD_LETTER_POISON: &str = "x-dead-letter-poison";
+/// Time to live of an exchange in milliseconds, counted from its timestamp.
+pub const HEADER_TTL: &str = "x-ttl";
+/// Id of the request exchange a reply answers.
+pub const HEADER_CORRELATION_ID: &str = "x-correlation-id";
+/// Topic the reply to a request must be published to.
+pub const HEADER_REPLY_TO: &str = "x-reply-to";
+/// RFC 3339 time before which the broker holds the exchange back.
+pub const HEADER_DELIVER_AT: &str = "deliver-at";
+/// Prefix of the temporary reply queues, which are not stored by the broker.
+pub const REPLY_TOPIC_PREFIX: &str = "REPLY.";
 
 #[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
 pub struct Exchange {
@@ -17,13 +43,17 @@ impl Default for Exchange {
         Self {
             timestamp: Local::now().naive_local(),
             topic: Default::default(),
-            headers: Default::default(),
+            headers: HashMap::from([(HEADER_MESSAGE_ID.into(), new_id())]),
             message: Default::default(),
             tenant: Default::default(),
         }
     }
 }
 
+fn new_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
 impl Exchange {
     pub fn new(
         message: &[u8],
@@ -31,14 +61,75 @@ impl Exchange {
         tenant: Option<String>,
         headers: HashMap<String, String>,
     ) -> Exchange {
-        Exchange {
+        let mut exchange = Exchange {
             topic: topic.into(),
             message: message.to_vec(),
             tenant,
             headers,
             ..Default::default()
+        };
+        exchange.ensure_id();
+        exchange
+    }
+
+    /// Id of the exchange, empty if it was published without one.
+    pub fn id(&self) -> &str {
+        self.headers
+            .get(HEADER_MESSAGE_ID)
+            .map(String::as_str)
+            .unwrap_or_default()
+    }
+
+    /// What consumers acknowledge: the delivery id set by the broker, or the
+    /// message id when delivered by a broker that does not set it.
+    pub fn delivery_id(&self) -> &str {
+        self.headers
+            .get(HEADER_DELIVERY_ID)
+            .map(String::as_str)
+            .unwrap_or_else(|| self.id())
+    }
+
+    /// Give the exchange an id if it has none, returning whether it got one.
+    pub fn ensure_id(&mut self) -> bool {
+        if !self.id().is_empty() {
+            return false;
         }
+        self.headers.insert(HEADER_MESSAGE_ID.into(), new_id());
+        true
+    }
+
+    /// The same exchange under a fresh id.
+    pub fn with_new_id(mut self) -> Exchange {
+        self.headers.insert(HEADER_MESSAGE_ID.into(), new_id());
+        self
+    }
+    /// Encode a typed payload, recording the codec in the content-type header.
+    pub fn from_payload<T: Serialize>(
+        topic: &str,
+        payload: &T,
+        codec: Codec,
+    ) -> Result<Exchange, CodecError> {
+        let headers = HashMap::from([(
+            HEADER_CONTENT_TYPE.to_string(),
+            codec.content_type().to_string(),
+        )]);
+        Ok(Exchange::new(&codec.encode(payload)?, topic, None, headers))
     }
+
+    /// Decode the message with the codec of its content-type header.
+    pub fn payload<T: DeserializeOwned>(&self) -> Result<T, CodecError> {
+        let content_type = self
+            .headers
+            .get(HEADER_CONTENT_TYPE)
+            .ok_or_else(|| CodecError {
+                msg: format!("exchange {} has no {HEADER_CONTENT_TYPE} header", self.id()),
+            })?;
+        let codec = Codec::from_content_type(content_type).ok_or_else(|| CodecError {
+            msg: format!("unsupported content type {content_type}"),
+        })?;
+        codec.decode(&self.message)
+    }
+
     pub fn deserialize(s: &[u8]) -> Result<Exchange, Box<bincode::ErrorKind>> {
         bincode::deserialize(s)
     }
@@ -47,8 +138,146 @@ impl Exchange {
         bincode::serialize(&self)
     }
 
+    pub fn with_ttl(mut self, ttl: Duration) -> Exchange {
+        self.headers
+            .insert(HEADER_TTL.into(), ttl.as_millis().to_string());
+        self
+    }
+
+    /// When the exchange expires according to its ttl header, if any.
+    pub fn expires_at(&self) -> Option<NaiveDateTime> {
+        let ttl = self.headers.get(HEADER_TTL)?.parse::<i64>().ok()?;
+        self.timestamp
+            .checked_add_signed(chrono::Duration::milliseconds(ttl))
+    }
+
+    /// Have the broker hold the exchange back until that time.
+    pub fn with_deliver_at(mut self, deliver_at: DateTime<Local>) -> Exchange {
+        self.headers
+            .insert(HEADER_DELIVER_AT.into(), deliver_at.to_rfc3339());
+        self
+    }
+
+    /// When the exchange is due according to its deliver-at header, `None`
+    /// without header, an error if the header is not a RFC 3339 time.
+    pub fn deliver_at(&self) -> Result<Option<NaiveDateTime>, chrono::ParseError> {
+        let Some(deliver_at) = self.headers.get(HEADER_DELIVER_AT) else {
+            return Ok(None);
+        };
+        let deliver_at = DateTime::parse_from_rfc3339(deliver_at)?;
+        Ok(Some(deliver_at.with_timezone(&Local).naive_local()))
+    }
+
+    /// A fresh topic for the temporary reply queue of a client.
+    pub fn reply_topic() -> String {
+        format!("{REPLY_TOPIC_PREFIX}{}", uuid::Uuid::new_v4().simple())
+    }
+
+    pub fn is_reply_topic(topic: &str) -> bool {
+        topic.to_uppercase().starts_with(REPLY_TOPIC_PREFIX)
+    }
+
+    /// Turn the exchange into a request expecting its reply on that topic.
+    pub fn with_reply_to(mut self, reply_to: &str) -> Exchange {
+        self.headers.insert(HEADER_REPLY_TO.into(), reply_to.into());
+        self
+    }
+
+    pub fn reply_to(&self) -> Option<&str> {
+        self.headers.get(HEADER_REPLY_TO).map(String::as_str)
+    }
+
+    pub fn correlation_id(&self) -> Option<&str> {
+        self.headers.get(HEADER_CORRELATION_ID).map(String::as_str)
+    }
+
+    /// Build the reply to this request, `None` if no reply is expected.
+    pub fn reply(&self, message: &[u8], mut headers: HashMap<String, String>) -> Option<Exchange> {
+        let reply_to = self.reply_to()?;
+        headers.insert(HEADER_CORRELATION_ID.into(), self.id().to_owned());
+        Some(Exchange::new(
+            message,
+            reply_to,
+            self.tenant.clone(),
+            headers,
+        ))
+    }
+
     pub fn get_message_as_string(msg: &[u8]) -> String {
         let s = String::from_utf8_lossy(msg);
         s.to_string()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use chrono::{Local, NaiveDateTime};
+    use serde::Serialize;
+
+    use super::Exchange;
+    use crate::codec::{Codec, HEADER_CONTENT_TYPE};
+
+    #[test]
+    fn reply_correlates_request() {
+        let reply_topic = Exchange::reply_topic();
+        assert!(Exchange::is_reply_topic(&reply_topic.to_lowercase()));
+        let request = Exchange::new(b"ping", "Animal", Some("artcoded".into()), HashMap::new())
+            .with_reply_to(&reply_topic);
+        let reply = request.reply(b"pong", HashMap::new()).unwrap();
+        assert_eq!(reply_topic, reply.topic);
+        assert_eq!(Some(request.id()), reply.correlation_id());
+        assert_ne!(request.id(), reply.id());
+        assert_eq!(request.tenant, reply.tenant);
+        assert!(reply.reply(b"", HashMap::new()).is_none());
+    }
+
+    /// Encoding of the exchanges before they had an id.
+    #[derive(Serialize)]
+    struct BaselineExchange {
+        timestamp: NaiveDateTime,
+        topic: String,
+        tenant: Option<String>,
+        headers: HashMap<String, String>,
+        message: Vec<u8>,
+    }
+
+    #[test]
+    fn decode_baseline_exchange() {
+        let baseline = bincode::serialize(&BaselineExchange {
+            timestamp: Local::now().naive_local(),
+            topic: "Animal".into(),
+            tenant: Some("artcoded".into()),
+            headers: HashMap::from([("kind".into(), "cat".into())]),
+            message: b"Hello".to_vec(),
+        })
+        .unwrap();
+        let mut exchange = Exchange::deserialize(&baseline).unwrap();
+        assert_eq!("Animal", exchange.topic);
+        assert_eq!(b"Hello".to_vec(), exchange.message);
+        assert_eq!("", exchange.id());
+        assert!(exchange.ensure_id());
+        assert!(!exchange.id().is_empty());
+        assert!(!exchange.ensure_id());
+    }
+
+    #[test]
+    fn typed_payload() {
+        let exchange =
+            Exchange::from_payload("Animal", &vec!["cat", "dog"], Codec::MessagePack).unwrap();
+        assert_eq!(
+            Some("application/msgpack"),
+            exchange
+                .headers
+                .get(HEADER_CONTENT_TYPE)
+                .map(String::as_str)
+        );
+        assert_eq!(
+            vec!["cat", "dog"],
+            exchange.payload::<Vec<String>>().unwrap()
+        );
+        let raw = Exchange::new(b"cat", "Animal", None, HashMap::new());
+        assert!(raw.payload::<String>().is_err());
+    }
+}
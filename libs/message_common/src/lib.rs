@@ -1,11 +1,103 @@
+use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
 
+pub mod codec;
 pub mod exchange;
+pub mod topic;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Options of a subscription, `None` falling back to the broker defaults.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SubscribeOptions {
+    /// Join a consumer group: each exchange is delivered to only one member of the group.
+    #[serde(default)]
+    pub group: Option<String>,
+    /// Only receive exchanges published for that tenant, the public tenant by default.
+    #[serde(default)]
+    pub tenant: Option<String>,
+    /// Replay the retained exchanges from that position before the live ones.
+    #[serde(default)]
+    pub from: Option<StartPosition>,
+    /// What to do when the consumer falls behind, unbounded by default.
+    #[serde(default)]
+    pub backpressure: Option<Backpressure>,
+}
+
+/// Bounds the exchanges pending for a consumer, published but not yet
+/// acknowledged, and what the broker does beyond that bound.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Backpressure {
+    pub buffer: u64,
+    pub policy: SlowConsumerPolicy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowConsumerPolicy {
+    /// Publishers wait until the consumer catches up.
+    Block,
+    /// Skip the oldest pending exchanges.
+    DropOldest,
+    /// Skip the exchanges published while the buffer is full.
+    DropNewest,
+    /// Close the connection of the consumer.
+    Disconnect,
+}
+
+/// Where a replaying subscription starts in each topic log it matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StartPosition {
+    /// Offset in the topic log, the earliest retained one if no longer retained.
+    Offset(u64),
+    /// First exchange published at or after that time.
+    Timestamp(NaiveDateTime),
+}
+
+/// A subscription of a connection, as confirmed or listed by the broker.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SubscriptionInfo {
+    pub topic: String,
+    pub tenant: String,
+    #[serde(default)]
+    pub group: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum TextMessage {
     Connect(String),
     Subscribe(String),
+    SubscribeWith {
+        topic: String,
+        #[serde(default)]
+        options: SubscribeOptions,
+    },
+    Ack(String),
+    Nack(String),
+    /// Ask the broker to send the dead-lettered exchanges to this connection.
+    InspectDeadLetters,
+    /// Republish the dead-lettered exchanges to their original topic.
+    ReplayDeadLetters,
+    /// Open a temporary queue receiving the exchanges published to that reply
+    /// topic, until the connection closes.
+    ReplyQueue(String),
+    /// Stop receiving the exchanges of a subscription, found by its topic and
+    /// the tenant and group of the options. Leaving a group keeps its
+    /// subscription for the other members.
+    Unsubscribe {
+        topic: String,
+        #[serde(default)]
+        options: SubscribeOptions,
+    },
+    /// Ask the broker for the subscriptions of this connection.
+    ListSubscriptions,
+    /// Sent by the broker once a subscription is active. Subscribing again
+    /// to the same topic is confirmed without duplicating the subscription.
+    Subscribed(SubscriptionInfo),
+    /// Sent by the broker once a subscription is removed.
+    Unsubscribed(SubscriptionInfo),
+    /// Sent by the broker in answer to `ListSubscriptions`.
+    Subscriptions(Vec<SubscriptionInfo>),
+    /// Sent by the broker when it rejects a message of the connection.
+    Error(String),
 }
 
 impl TextMessage {
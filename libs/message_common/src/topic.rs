@@ -0,0 +1,196 @@
+use std::{fmt::Display, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+pub const TOPIC_SEPARATOR: char = '.';
+pub const SINGLE_LEVEL_WILDCARD: &str = "*";
+pub const MULTI_LEVEL_WILDCARD: &str = "#";
+
+/// One level of a topic pattern.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TopicSegment {
+    Literal(String),
+    /// `*`: exactly one level
+    Single,
+    /// `#`: zero or more levels
+    Multi,
+}
+
+/// A subscription topic, e.g. `delta.*` or `mandatee.#.created`.
+/// Topics are case-insensitive and normalized to uppercase.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TopicPattern {
+    pattern: String,
+    segments: Vec<TopicSegment>,
+}
+
+#[derive(Debug)]
+pub struct TopicPatternError {
+    pub msg: String,
+}
+
+impl Display for TopicPatternError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl std::error::Error for TopicPatternError {}
+
+impl TopicPattern {
+    pub fn parse(pattern: &str) -> Result<TopicPattern, TopicPatternError> {
+        let pattern = pattern.trim().to_uppercase();
+        if pattern.is_empty() {
+            return Err(TopicPatternError {
+                msg: "empty topic".into(),
+            });
+        }
+        let segments = pattern
+            .split(TOPIC_SEPARATOR)
+            .map(|segment| match segment {
+                "" => Err(TopicPatternError {
+                    msg: format!("empty level in topic {pattern}"),
+                }),
+                SINGLE_LEVEL_WILDCARD => Ok(TopicSegment::Single),
+                MULTI_LEVEL_WILDCARD => Ok(TopicSegment::Multi),
+                s if s.contains(['*', '#']) => Err(TopicPatternError {
+                    msg: format!("wildcard must occupy a whole level in topic {pattern}"),
+                }),
+                s => Ok(TopicSegment::Literal(s.to_string())),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(TopicPattern { pattern, segments })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn segments(&self) -> &[TopicSegment] {
+        &self.segments
+    }
+
+    pub fn is_wildcard(&self) -> bool {
+        self.segments
+            .iter()
+            .any(|s| !matches!(s, TopicSegment::Literal(_)))
+    }
+
+    pub fn matches(&self, topic: &str) -> bool {
+        let topic = topic.to_uppercase();
+        let levels = topic.split(TOPIC_SEPARATOR).collect::<Vec<_>>();
+        matches_levels(&self.segments, &levels)
+    }
+
+    /// Whether every topic matched by the other pattern is also matched by this one.
+    pub fn covers(&self, other: &TopicPattern) -> bool {
+        covers_segments(&self.segments, &other.segments)
+    }
+}
+
+/// Whether every topic matched by `inner` is also matched by `outer`.
+fn covers_segments(outer: &[TopicSegment], inner: &[TopicSegment]) -> bool {
+    match (outer.split_first(), inner.split_first()) {
+        (None, None) => true,
+        (Some((TopicSegment::Multi, rest)), _) => {
+            (0..=inner.len()).any(|skip| covers_segments(rest, &inner[skip..]))
+        }
+        (Some(_), Some((TopicSegment::Multi, _))) => false,
+        (Some((TopicSegment::Single, rest)), Some((_, inner_rest))) => {
+            covers_segments(rest, inner_rest)
+        }
+        (
+            Some((TopicSegment::Literal(outer_literal), rest)),
+            Some((TopicSegment::Literal(inner_literal), inner_rest)),
+        ) => outer_literal == inner_literal && covers_segments(rest, inner_rest),
+        _ => false,
+    }
+}
+
+fn matches_levels(segments: &[TopicSegment], levels: &[&str]) -> bool {
+    match segments.split_first() {
+        None => levels.is_empty(),
+        Some((TopicSegment::Multi, rest)) => {
+            (0..=levels.len()).any(|skip| matches_levels(rest, &levels[skip..]))
+        }
+        Some((TopicSegment::Single, rest)) => {
+            !levels.is_empty() && matches_levels(rest, &levels[1..])
+        }
+        Some((TopicSegment::Literal(literal), rest)) => {
+            levels.first().map(|l| l == literal).unwrap_or(false)
+                && matches_levels(rest, &levels[1..])
+        }
+    }
+}
+
+impl FromStr for TopicPattern {
+    type Err = TopicPatternError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        TopicPattern::parse(s)
+    }
+}
+
+impl TryFrom<String> for TopicPattern {
+    type Error = TopicPatternError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        TopicPattern::parse(&value)
+    }
+}
+
+impl From<TopicPattern> for String {
+    fn from(value: TopicPattern) -> Self {
+        value.pattern
+    }
+}
+
+impl Display for TopicPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.pattern)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TopicPattern;
+
+    #[test]
+    fn match_wildcards() {
+        let exact = TopicPattern::parse("Animal").unwrap();
+        assert!(exact.matches("animal"));
+        assert!(!exact.is_wildcard());
+
+        let single = TopicPattern::parse("delta.*").unwrap();
+        assert!(single.matches("delta.created"));
+        assert!(!single.matches("delta"));
+        assert!(!single.matches("delta.created.person"));
+
+        let multi = TopicPattern::parse("mandatee.#.created").unwrap();
+        assert!(multi.matches("mandatee.created"));
+        assert!(multi.matches("mandatee.person.created"));
+        assert!(multi.matches("mandatee.person.address.created"));
+        assert!(!multi.matches("mandatee.person.deleted"));
+
+        assert!(TopicPattern::parse("#").unwrap().matches("anything.at.all"));
+    }
+
+    #[test]
+    fn cover_patterns() {
+        let parse = |p| TopicPattern::parse(p).unwrap();
+        assert!(parse("delta.*").covers(&parse("delta.created")));
+        assert!(parse("delta.*").covers(&parse("delta.*")));
+        assert!(!parse("delta.*").covers(&parse("delta.#")));
+        assert!(parse("delta.#").covers(&parse("delta.*.created")));
+        assert!(parse("#").covers(&parse("#.created")));
+        assert!(!parse("delta.created").covers(&parse("delta.*")));
+    }
+
+    #[test]
+    fn reject_invalid_patterns() {
+        assert!(TopicPattern::parse("").is_err());
+        assert!(TopicPattern::parse("delta..created").is_err());
+        assert!(TopicPattern::parse("delta.cr*").is_err());
+    }
+}
@@ -0,0 +1,242 @@
+use std::{env::var, path::Path, sync::Arc};
+
+use mu_rust_message_common::{exchange::Exchange, topic::TopicPattern};
+use mu_rust_service_common::PUBLIC_TENANT;
+use serde::Deserialize;
+
+use crate::{
+    auth::Claims,
+    constants::PUB_ACL_CONFIG,
+    exchange_manager::{to_service_error, ExchangeError},
+};
+
+/// Any service, in the `service` field of a rule.
+const ANY_SERVICE: &str = "*";
+
+/// Grants a service the topics it may publish to and subscribe on, within
+/// some tenants (all of them when omitted).
+#[derive(Debug, Deserialize)]
+pub struct AclRule {
+    pub service: String,
+    #[serde(default)]
+    pub publish: Vec<TopicPattern>,
+    #[serde(default)]
+    pub subscribe: Vec<TopicPattern>,
+    #[serde(default)]
+    pub tenants: Option<Vec<String>>,
+}
+
+impl AclRule {
+    fn applies(&self, service_id: &str, tenant: &str) -> bool {
+        (self.service == ANY_SERVICE || self.service == service_id)
+            && self
+                .tenants
+                .as_ref()
+                .map(|tenants| tenants.iter().any(|t| t == tenant))
+                .unwrap_or(true)
+    }
+}
+
+/// Access control list loaded from the TOML or YAML file set in
+/// `PUB_ACL_CONFIG`, the format following the file extension:
+///
+/// ```toml
+/// [[rule]]
+/// service = "delta-notifier"
+/// publish = ["delta.#"]
+/// subscribe = ["person.*"]
+/// tenants = ["artcoded"]
+/// ```
+///
+/// Whatever no rule grants is denied. Without file, everything is allowed.
+#[derive(Debug, Default, Deserialize)]
+pub struct AccessControl {
+    #[serde(default, rename = "rule")]
+    rules: Vec<AclRule>,
+}
+
+impl AccessControl {
+    pub fn load() -> Result<Option<AccessControl>, ExchangeError> {
+        let Ok(path) = var(PUB_ACL_CONFIG) else {
+            return Ok(None);
+        };
+        let content = std::fs::read_to_string(&path).map_err(to_service_error)?;
+        let yaml = Path::new(&path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.eq_ignore_ascii_case("yaml") || e.eq_ignore_ascii_case("yml"))
+            .unwrap_or(false);
+        let acl = if yaml {
+            serde_yaml::from_str(&content).map_err(to_service_error)?
+        } else {
+            toml::from_str(&content).map_err(to_service_error)?
+        };
+        Ok(Some(acl))
+    }
+
+    pub fn allows_subscription(
+        &self,
+        service_id: &str,
+        pattern: &TopicPattern,
+        tenant: &str,
+    ) -> bool {
+        self.rules
+            .iter()
+            .filter(|rule| rule.applies(service_id, tenant))
+            .any(|rule| rule.subscribe.iter().any(|t| t.covers(pattern)))
+    }
+
+    pub fn allows_publish(&self, service_id: &str, topic: &str, tenant: &str) -> bool {
+        self.rules
+            .iter()
+            .filter(|rule| rule.applies(service_id, tenant))
+            .any(|rule| rule.publish.iter().any(|t| t.matches(topic)))
+    }
+}
+
+/// Whether the token claims and the ACL, if any, let the service see the
+/// messages of that tenant.
+pub fn allows_tenant(
+    claims: Option<&Claims>,
+    acl: Option<&AccessControl>,
+    service_id: &str,
+    tenant: &str,
+) -> bool {
+    claims.map(|c| c.allows_tenant(tenant)).unwrap_or(true)
+        && acl
+            .map(|acl| {
+                acl.rules
+                    .iter()
+                    .any(|rule| rule.applies(service_id, tenant))
+            })
+            .unwrap_or(true)
+}
+
+fn denied(msg: String) -> ExchangeError {
+    ExchangeError { msg }
+}
+
+/// Check a subscription against the token claims and the ACL, if any.
+pub fn check_subscribe(
+    claims: Option<&Claims>,
+    acl: Option<&AccessControl>,
+    service_id: &str,
+    topic: &str,
+    tenant: Option<&str>,
+) -> Result<(), ExchangeError> {
+    let pattern = TopicPattern::parse(topic).map_err(to_service_error)?;
+    let tenant = tenant.unwrap_or(PUBLIC_TENANT);
+    if let Some(claims) = claims {
+        if !claims.allows_tenant(tenant) || !claims.allows_subscription(&pattern) {
+            return Err(denied(format!(
+                "token does not grant subscribing to {topic} for tenant {tenant}"
+            )));
+        }
+    }
+    if let Some(acl) = acl {
+        if !acl.allows_subscription(service_id, &pattern, tenant) {
+            return Err(denied(format!(
+                "{service_id} is not allowed to subscribe to {topic} for tenant {tenant}"
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// What a connected service may subscribe to: the claims of its token and
+/// the ACL, if any. Kept by the exchange manager for each connection, so that
+/// group deliveries only go to the members allowed to receive them.
+#[derive(Debug, Clone, Default)]
+pub struct Grants {
+    claims: Option<Claims>,
+    acl: Arc<Option<AccessControl>>,
+}
+
+impl Grants {
+    pub fn new(claims: Option<Claims>, acl: Arc<Option<AccessControl>>) -> Grants {
+        Grants { claims, acl }
+    }
+
+    pub fn check_subscribe(
+        &self,
+        service_id: &str,
+        topic: &str,
+        tenant: &str,
+    ) -> Result<(), ExchangeError> {
+        check_subscribe(
+            self.claims.as_ref(),
+            self.acl.as_ref().as_ref(),
+            service_id,
+            topic,
+            Some(tenant),
+        )
+    }
+}
+
+/// Check a publication against the token claims and the ACL, if any.
+/// Replies are always allowed.
+pub fn check_publish(
+    claims: Option<&Claims>,
+    acl: Option<&AccessControl>,
+    service_id: &str,
+    exchange: &Exchange,
+) -> Result<(), ExchangeError> {
+    if Exchange::is_reply_topic(&exchange.topic) {
+        return Ok(());
+    }
+    let topic = &exchange.topic;
+    let tenant = exchange.tenant.as_deref().unwrap_or(PUBLIC_TENANT);
+    if let Some(claims) = claims {
+        if !claims.allows_tenant(tenant) || !claims.allows_publish(topic) {
+            return Err(denied(format!(
+                "token does not grant publishing to {topic} for tenant {tenant}"
+            )));
+        }
+    }
+    if let Some(acl) = acl {
+        if !acl.allows_publish(service_id, topic, tenant) {
+            return Err(denied(format!(
+                "{service_id} is not allowed to publish to {topic} for tenant {tenant}"
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use mu_rust_message_common::exchange::Exchange;
+
+    use super::{check_publish, check_subscribe, AccessControl};
+
+    #[test]
+    fn enforce_acl() {
+        let acl: AccessControl = serde_yaml::from_str(
+            r#"
+            rule:
+              - service: notifier
+                publish: ["delta.#"]
+                tenants: ["artcoded"]
+              - service: "*"
+                subscribe: ["person.*"]
+            "#,
+        )
+        .unwrap();
+        let delta = Exchange::new(
+            b"",
+            "delta.created",
+            Some("artcoded".into()),
+            HashMap::new(),
+        );
+        assert!(check_publish(None, Some(&acl), "notifier", &delta).is_ok());
+        assert!(check_publish(None, Some(&acl), "other", &delta).is_err());
+        let public_delta = Exchange::new(b"", "delta.created", None, HashMap::new());
+        assert!(check_publish(None, Some(&acl), "notifier", &public_delta).is_err());
+
+        assert!(check_subscribe(None, Some(&acl), "other", "person.created", None).is_ok());
+        assert!(check_subscribe(None, Some(&acl), "other", "person.#", None).is_err());
+        assert!(check_subscribe(None, None, "other", "#", None).is_ok());
+    }
+}
@@ -0,0 +1,283 @@
+use std::{collections::HashMap, fmt::Display, sync::Arc};
+
+use axum::{
+    extract::Path,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{delete, get},
+    Extension, Json, Router,
+};
+use chrono::{DateTime, FixedOffset, Local};
+use mu_rust_message_common::{codec::Codec, exchange::Exchange};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{
+    acl::{check_publish, AccessControl},
+    auth::{authenticating, identify, Authenticator, Identity},
+    exchange_manager::{check_exchange, wait_for_consumers, ExchangeManager},
+    metrics::Metrics,
+    subscribers::Subscribers,
+    tls::ClientCertificate,
+};
+
+/// Service id checked against the ACL for anonymous HTTP publications.
+const ANONYMOUS_SERVICE: &str = "anonymous";
+
+/// Body of `POST /topics/{topic}/messages`. The payload is published as a
+/// JSON encoded exchange.
+#[derive(Debug, Deserialize)]
+pub struct PublishRequest {
+    pub payload: serde_json::Value,
+    #[serde(default)]
+    pub tenant: Option<String>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+impl PublishRequest {
+    fn into_exchange(self, topic: &str) -> Result<Exchange, ApiError> {
+        let mut exchange = Exchange::from_payload(topic, &self.payload, Codec::Json)
+            .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e))?;
+        exchange.tenant = self.tenant;
+        exchange.headers.extend(self.headers);
+        Ok(exchange)
+    }
+}
+
+/// Body of `POST /admin/schedules`: the exchange is published once at
+/// `deliver_at`, or at every occurrence of the `cron` expression.
+#[derive(Debug, Deserialize)]
+pub struct ScheduleRequest {
+    pub topic: String,
+    #[serde(flatten)]
+    pub exchange: PublishRequest,
+    #[serde(default)]
+    pub deliver_at: Option<DateTime<FixedOffset>>,
+    #[serde(default)]
+    pub cron: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Published {
+    pub id: String,
+}
+
+/// Error returned by the HTTP API as `{"error": "..."}`.
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    msg: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, e: impl Display) -> ApiError {
+        ApiError {
+            status,
+            msg: e.to_string(),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(serde_json::json!({ "error": self.msg }))).into_response()
+    }
+}
+
+fn identify_request(
+    headers: &HeaderMap,
+    authenticator: &Option<Authenticator>,
+    client_certificate: Option<Extension<ClientCertificate>>,
+) -> Result<Identity, ApiError> {
+    identify(
+        authenticator.as_ref(),
+        headers,
+        client_certificate.map(|Extension(c)| c),
+    )
+    .map_err(|e| ApiError::new(StatusCode::UNAUTHORIZED, e))
+}
+
+pub async fn publish(
+    Path(topic): Path<String>,
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<Mutex<ExchangeManager>>>,
+    Extension(authenticator): Extension<Arc<Option<Authenticator>>>,
+    Extension(acl): Extension<Arc<Option<AccessControl>>>,
+    client_certificate: Option<Extension<ClientCertificate>>,
+    Json(request): Json<PublishRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let identity = identify_request(&headers, &authenticator, client_certificate)?;
+    let exchange = request.into_exchange(&topic)?;
+    let service_id = identity.service_id.as_deref().unwrap_or(ANONYMOUS_SERVICE);
+    check_publish(
+        identity.claims.as_ref(),
+        acl.as_ref().as_ref(),
+        service_id,
+        &exchange,
+    )
+    .map_err(|e| ApiError::new(StatusCode::FORBIDDEN, e))?;
+    check_exchange(&exchange).map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e))?;
+    let binary = exchange
+        .serialize()
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    let throttled = state
+        .lock()
+        .await
+        .publish(binary)
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    if let Some(key) = throttled {
+        wait_for_consumers(&state, &key).await;
+    }
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(Published {
+            id: exchange.id().to_owned(),
+        }),
+    ))
+}
+
+/// Prometheus metrics, read without locking the exchange manager.
+pub async fn metrics(Extension(metrics): Extension<Metrics>) -> Result<String, ApiError> {
+    metrics
+        .encode()
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e))
+}
+
+/// Admin endpoints, for callers with admin claims or listed in
+/// `PUB_ADMIN_SERVICES`.
+pub fn admin_routes() -> Router {
+    Router::new()
+        .route("/subscribers", get(list_subscribers))
+        .route("/subscribers/:service_id", delete(disconnect))
+        .route("/subscriptions", get(list_subscriptions))
+        .route("/topics", get(topic_depths))
+        .route("/schedules", get(list_schedules).post(schedule))
+        .route("/schedules/:id", delete(cancel_schedule))
+}
+
+fn authorize_admin(
+    headers: &HeaderMap,
+    authenticator: &Option<Authenticator>,
+    client_certificate: Option<Extension<ClientCertificate>>,
+) -> Result<(), ApiError> {
+    let identity = identify_request(headers, authenticator, client_certificate)?;
+    if !identity.is_admin(authenticating(authenticator.as_ref())) {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "admin access required",
+        ));
+    }
+    Ok(())
+}
+
+async fn list_subscribers(
+    headers: HeaderMap,
+    Extension(subscribers): Extension<Subscribers>,
+    Extension(authenticator): Extension<Arc<Option<Authenticator>>>,
+    client_certificate: Option<Extension<ClientCertificate>>,
+) -> Result<impl IntoResponse, ApiError> {
+    authorize_admin(&headers, &authenticator, client_certificate)?;
+    Ok(Json(subscribers.service_ids()))
+}
+
+async fn list_subscriptions(
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<Mutex<ExchangeManager>>>,
+    Extension(authenticator): Extension<Arc<Option<Authenticator>>>,
+    client_certificate: Option<Extension<ClientCertificate>>,
+) -> Result<impl IntoResponse, ApiError> {
+    authorize_admin(&headers, &authenticator, client_certificate)?;
+    Ok(Json(state.lock().await.subscriptions()))
+}
+
+async fn topic_depths(
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<Mutex<ExchangeManager>>>,
+    Extension(authenticator): Extension<Arc<Option<Authenticator>>>,
+    client_certificate: Option<Extension<ClientCertificate>>,
+) -> Result<impl IntoResponse, ApiError> {
+    authorize_admin(&headers, &authenticator, client_certificate)?;
+    Ok(Json(state.lock().await.topic_depths()))
+}
+
+async fn disconnect(
+    Path(service_id): Path<String>,
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<Mutex<ExchangeManager>>>,
+    Extension(authenticator): Extension<Arc<Option<Authenticator>>>,
+    client_certificate: Option<Extension<ClientCertificate>>,
+) -> Result<StatusCode, ApiError> {
+    authorize_admin(&headers, &authenticator, client_certificate)?;
+    let mut em = state.lock().await;
+    if !em.connected().contains(&service_id) {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            format!("{service_id} not connected"),
+        ));
+    }
+    em.close_connection(&service_id);
+    tracing::info!("{service_id} disconnected by an admin");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_schedules(
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<Mutex<ExchangeManager>>>,
+    Extension(authenticator): Extension<Arc<Option<Authenticator>>>,
+    client_certificate: Option<Extension<ClientCertificate>>,
+) -> Result<impl IntoResponse, ApiError> {
+    authorize_admin(&headers, &authenticator, client_certificate)?;
+    Ok(Json(state.lock().await.schedules()))
+}
+
+async fn schedule(
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<Mutex<ExchangeManager>>>,
+    Extension(authenticator): Extension<Arc<Option<Authenticator>>>,
+    client_certificate: Option<Extension<ClientCertificate>>,
+    Json(request): Json<ScheduleRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    authorize_admin(&headers, &authenticator, client_certificate)?;
+    let exchange = request.exchange.into_exchange(&request.topic)?;
+    let mut em = state.lock().await;
+    let id = match (request.deliver_at, request.cron) {
+        (Some(deliver_at), None) => {
+            let exchange = exchange.with_deliver_at(deliver_at.with_timezone(&Local));
+            check_exchange(&exchange).map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e))?;
+            let binary = exchange
+                .serialize()
+                .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+            em.publish(binary)
+                .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+            exchange.id().to_owned()
+        }
+        (None, Some(cron)) => em
+            .schedule(exchange, &cron)
+            .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e))?,
+        _ => {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "either deliver_at or cron is required",
+            ))
+        }
+    };
+    Ok((StatusCode::CREATED, Json(Published { id })))
+}
+
+async fn cancel_schedule(
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<Mutex<ExchangeManager>>>,
+    Extension(authenticator): Extension<Arc<Option<Authenticator>>>,
+    client_certificate: Option<Extension<ClientCertificate>>,
+) -> Result<StatusCode, ApiError> {
+    authorize_admin(&headers, &authenticator, client_certificate)?;
+    if !state.lock().await.cancel_schedule(&id) {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            format!("no schedule {id}"),
+        ));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
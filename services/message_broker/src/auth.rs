@@ -0,0 +1,185 @@
+use std::env::var;
+
+use axum::http::{header::AUTHORIZATION, HeaderMap};
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use mu_rust_message_common::topic::TopicPattern;
+use serde::Deserialize;
+
+use crate::{
+    constants::{PUB_ADMIN_SERVICES, PUB_JWT_AUDIENCE, PUB_JWT_SECRET, PUB_TLS_CLIENT_CA},
+    exchange_manager::{to_service_error, ExchangeError},
+    tls::ClientCertificate,
+};
+
+/// Claims of the bearer token a service connects with. The subject is its
+/// service id; missing `topics` or `tenants` claims grant them all.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Claims {
+    pub sub: String,
+    #[serde(default)]
+    pub topics: Option<Vec<TopicPattern>>,
+    #[serde(default)]
+    pub tenants: Option<Vec<String>>,
+    /// Grants the admin endpoints.
+    #[serde(default)]
+    pub admin: bool,
+}
+
+impl Claims {
+    pub fn allows_tenant(&self, tenant: &str) -> bool {
+        self.tenants
+            .as_ref()
+            .map(|tenants| tenants.iter().any(|t| t == tenant))
+            .unwrap_or(true)
+    }
+
+    /// A subscription is allowed if one of the granted patterns covers it.
+    pub fn allows_subscription(&self, pattern: &TopicPattern) -> bool {
+        self.topics
+            .as_ref()
+            .map(|topics| topics.iter().any(|t| t.covers(pattern)))
+            .unwrap_or(true)
+    }
+
+    pub fn allows_publish(&self, topic: &str) -> bool {
+        self.topics
+            .as_ref()
+            .map(|topics| topics.iter().any(|t| t.matches(topic)))
+            .unwrap_or(true)
+    }
+}
+
+/// Validates the HS256 bearer token sent on the websocket upgrade, when
+/// `PUB_JWT_SECRET` is set. Without it, connections are not authenticated.
+pub struct Authenticator {
+    key: DecodingKey,
+    validation: Validation,
+}
+
+impl Authenticator {
+    pub fn from_env() -> Option<Authenticator> {
+        let secret = var(PUB_JWT_SECRET).ok()?;
+        Some(Authenticator::new(
+            secret.as_bytes(),
+            var(PUB_JWT_AUDIENCE).ok().as_deref(),
+        ))
+    }
+
+    /// Validate the tokens signed with that secret, and issued for that
+    /// audience if any.
+    pub fn new(secret: &[u8], audience: Option<&str>) -> Authenticator {
+        let mut validation = Validation::new(Algorithm::HS256);
+        if let Some(audience) = audience {
+            validation.set_audience(&[audience]);
+        }
+        Authenticator {
+            key: DecodingKey::from_secret(secret),
+            validation,
+        }
+    }
+
+    pub fn authenticate(&self, headers: &HeaderMap) -> Result<Claims, ExchangeError> {
+        let token = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| ExchangeError {
+                msg: "missing bearer token".into(),
+            })?;
+        decode::<Claims>(token.trim(), &self.key, &self.validation)
+            .map(|data| data.claims)
+            .map_err(to_service_error)
+    }
+}
+
+/// Who is calling the broker, from its bearer token and client certificate.
+/// Both are optional: without them, the caller is anonymous.
+#[derive(Debug, Default)]
+pub struct Identity {
+    pub service_id: Option<String>,
+    pub claims: Option<Claims>,
+}
+
+impl Identity {
+    /// Token claims decide, then the `PUB_ADMIN_SERVICES` list of service ids.
+    /// Anonymous callers are admins only when the broker does not
+    /// authenticate at all.
+    pub fn is_admin(&self, authenticating: bool) -> bool {
+        if let Some(claims) = &self.claims {
+            return claims.admin;
+        }
+        match &self.service_id {
+            Some(service_id) => var(PUB_ADMIN_SERVICES)
+                .map(|admins| admins.split(',').any(|admin| admin.trim() == service_id))
+                .unwrap_or(false),
+            None => !authenticating,
+        }
+    }
+}
+
+/// Whether callers are authenticated, by bearer token or client certificate.
+pub fn authenticating(authenticator: Option<&Authenticator>) -> bool {
+    authenticator.is_some() || var(PUB_TLS_CLIENT_CA).is_ok()
+}
+
+/// Identify the caller, rejecting invalid tokens and tokens that do not
+/// match the client certificate.
+pub fn identify(
+    authenticator: Option<&Authenticator>,
+    headers: &HeaderMap,
+    client_certificate: Option<ClientCertificate>,
+) -> Result<Identity, ExchangeError> {
+    let claims = authenticator
+        .map(|authenticator| authenticator.authenticate(headers))
+        .transpose()?;
+    let certified_id = client_certificate.map(|c| c.service_id);
+    if let (Some(claims), Some(certified_id)) = (&claims, &certified_id) {
+        if &claims.sub != certified_id {
+            return Err(ExchangeError {
+                msg: format!(
+                    "token of {} used with the certificate of {certified_id}",
+                    claims.sub
+                ),
+            });
+        }
+    }
+    Ok(Identity {
+        service_id: claims.as_ref().map(|c| c.sub.clone()).or(certified_id),
+        claims,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use axum::http::{header::AUTHORIZATION, HeaderMap};
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use mu_rust_message_common::topic::TopicPattern;
+    use serde_json::json;
+
+    use super::Authenticator;
+
+    #[test]
+    fn authenticate_bearer_token() {
+        let authenticator = Authenticator::new(b"secret", None);
+        let token = encode(
+            &Header::default(),
+            &json!({"sub": "Nordine", "exp": 4102444800u64, "topics": ["delta.#"], "tenants": ["artcoded"]}),
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+        let mut headers = HeaderMap::new();
+        assert!(authenticator.authenticate(&headers).is_err());
+        headers.insert(AUTHORIZATION, format!("Bearer {token}").parse().unwrap());
+        let claims = authenticator.authenticate(&headers).unwrap();
+        assert_eq!("Nordine", claims.sub);
+        assert!(claims.allows_tenant("artcoded"));
+        assert!(!claims.allows_tenant("public"));
+        assert!(claims.allows_subscription(&TopicPattern::parse("delta.*").unwrap()));
+        assert!(!claims.allows_subscription(&TopicPattern::parse("#").unwrap()));
+        assert!(claims.allows_publish("delta.created"));
+        assert!(!claims.allows_publish("animal"));
+
+        headers.insert(AUTHORIZATION, "Bearer forged".parse().unwrap());
+        assert!(authenticator.authenticate(&headers).is_err());
+    }
+}
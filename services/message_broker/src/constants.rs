@@ -1,5 +1,21 @@
 pub const PUB_PERSISTENT_DIR: &str = "PUB_PERSISTENT_DIR";
 pub const PUB_HOST: &str = "PUB_HOST";
 pub const PUB_PORT: &str = "PUB_PORT";
-pub const PUB_INTERVAL_CONSUMER: &str = "PUB_INTERVAL_CONSUMER";
 pub const PUB_INTERVAL_SYNC_FILE: &str = "PUB_INTERVAL_SYNC_FILE";
+pub const PUB_VISIBILITY_TIMEOUT: &str = "PUB_VISIBILITY_TIMEOUT";
+pub const PUB_MAX_DELIVERIES: &str = "PUB_MAX_DELIVERIES";
+pub const PUB_RETENTION_CONFIG: &str = "PUB_RETENTION_CONFIG";
+pub const PUB_SEGMENT_SIZE: &str = "PUB_SEGMENT_SIZE";
+pub const PUB_BATCH_SIZE: &str = "PUB_BATCH_SIZE";
+pub const PUB_INTERVAL_COMPACTION: &str = "PUB_INTERVAL_COMPACTION";
+pub const PUB_JWT_SECRET: &str = "PUB_JWT_SECRET";
+pub const PUB_JWT_AUDIENCE: &str = "PUB_JWT_AUDIENCE";
+pub const PUB_ACL_CONFIG: &str = "PUB_ACL_CONFIG";
+pub const PUB_TLS_CERT: &str = "PUB_TLS_CERT";
+pub const PUB_TLS_KEY: &str = "PUB_TLS_KEY";
+pub const PUB_TLS_CLIENT_CA: &str = "PUB_TLS_CLIENT_CA";
+pub const PUB_TLS_CLIENT_AUTH_REQUIRED: &str = "PUB_TLS_CLIENT_AUTH_REQUIRED";
+pub const PUB_ADMIN_SERVICES: &str = "PUB_ADMIN_SERVICES";
+pub const PUB_OUTBOUND_BUFFER_SIZE: &str = "PUB_OUTBOUND_BUFFER_SIZE";
+pub const PUB_DUPLICATE_SERVICE: &str = "PUB_DUPLICATE_SERVICE";
+pub const PUB_PUBLISH_BUFFER_SIZE: &str = "PUB_PUBLISH_BUFFER_SIZE";
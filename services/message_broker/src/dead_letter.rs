@@ -0,0 +1,175 @@
+use std::{collections::HashMap, path::Path};
+
+use chrono::Local;
+
+use mu_rust_message_common::exchange::{
+    Exchange, HEADER_DEAD_LETTER_CONSUMER, HEADER_DEAD_LETTER_POISON, HEADER_DEAD_LETTER_REASON,
+};
+use mu_rust_service_common::PUBLIC_TENANT;
+use queue_file::QueueFile;
+
+use crate::exchange_manager::{to_service_error, ExchangeError};
+
+pub const DEAD_LETTER_TOPIC: &str = "dead-letter";
+
+fn tenant_of(exchange: &Exchange) -> &str {
+    exchange.tenant.as_deref().unwrap_or(PUBLIC_TENANT)
+}
+
+/// Exchanges that could not be decoded or delivered, kept aside so that they
+/// no longer block the queue they came from.
+#[derive(Debug)]
+pub struct DeadLetterQueue {
+    queue: QueueFile,
+}
+
+impl DeadLetterQueue {
+    pub fn open(path: &Path) -> Result<DeadLetterQueue, ExchangeError> {
+        let queue = QueueFile::open(path).map_err(|e| ExchangeError {
+            msg: format!("{e:}"),
+        })?;
+        Ok(DeadLetterQueue { queue })
+    }
+
+    /// Dead-letter a record that is not a valid exchange, keeping its raw bytes as message.
+    pub fn push_poison(
+        &mut self,
+        tenant: &str,
+        exchange_binary: &[u8],
+        reason: &str,
+    ) -> Result<(), ExchangeError> {
+        let headers = HashMap::from([
+            (HEADER_DEAD_LETTER_REASON.to_string(), reason.to_string()),
+            (HEADER_DEAD_LETTER_POISON.to_string(), "true".to_string()),
+        ]);
+        let exchange = Exchange::new(
+            exchange_binary,
+            DEAD_LETTER_TOPIC,
+            Some(tenant.to_string()),
+            headers,
+        );
+        self.push_exchange(&exchange)
+    }
+
+    pub fn push(
+        &mut self,
+        exchange: &Exchange,
+        reason: &str,
+        consumer: Option<&str>,
+    ) -> Result<(), ExchangeError> {
+        let mut exchange = exchange.clone();
+        exchange
+            .headers
+            .insert(HEADER_DEAD_LETTER_REASON.into(), reason.into());
+        if let Some(consumer) = consumer {
+            exchange
+                .headers
+                .insert(HEADER_DEAD_LETTER_CONSUMER.into(), consumer.into());
+        }
+        self.push_exchange(&exchange)
+    }
+
+    fn push_exchange(&mut self, exchange: &Exchange) -> Result<(), ExchangeError> {
+        let binary = exchange.serialize().map_err(to_service_error)?;
+        self.queue.add(&binary).map_err(to_service_error)
+    }
+
+    /// The dead letters of the tenants that pass the filter.
+    pub fn list(
+        &mut self,
+        tenant_filter: impl Fn(&str) -> bool,
+    ) -> Result<Vec<Vec<u8>>, ExchangeError> {
+        Ok(self
+            .queue
+            .iter()
+            .filter(|binary| {
+                Exchange::deserialize(binary)
+                    .map(|exchange| tenant_filter(tenant_of(&exchange)))
+                    .unwrap_or(false)
+            })
+            .map(Vec::from)
+            .collect())
+    }
+
+    /// Remove the replayable dead letters of the tenants that pass the filter
+    /// and return them as fresh exchanges, timestamped now and without the
+    /// dead-letter headers, along with the consumer that failed them, if any.
+    /// Poison records stay in the queue.
+    pub fn drain_replayable(
+        &mut self,
+        tenant_filter: impl Fn(&str) -> bool,
+    ) -> Result<Vec<(Exchange, Option<String>)>, ExchangeError> {
+        let mut replayable = vec![];
+        let mut kept = vec![];
+        for binary in self.queue.iter() {
+            match Exchange::deserialize(&binary) {
+                Ok(exchange)
+                    if !exchange.headers.contains_key(HEADER_DEAD_LETTER_POISON)
+                        && tenant_filter(tenant_of(&exchange)) =>
+                {
+                    replayable.push(exchange)
+                }
+                _ => kept.push(Vec::from(binary)),
+            }
+        }
+        if replayable.is_empty() {
+            return Ok(vec![]);
+        }
+        self.queue.clear().map_err(to_service_error)?;
+        self.queue.add_n(kept).map_err(to_service_error)?;
+        Ok(replayable
+            .into_iter()
+            .map(|mut exchange| {
+                exchange.headers.remove(HEADER_DEAD_LETTER_REASON);
+                let consumer = exchange.headers.remove(HEADER_DEAD_LETTER_CONSUMER);
+                let exchange = Exchange {
+                    timestamp: Local::now().naive_local(),
+                    ..exchange
+                };
+                (exchange.with_new_id(), consumer)
+            })
+            .collect())
+    }
+
+    pub fn sync_all(&mut self) -> Result<(), ExchangeError> {
+        self.queue.sync_all().map_err(to_service_error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use mu_rust_message_common::exchange::{
+        Exchange, HEADER_DEAD_LETTER_CONSUMER, HEADER_DEAD_LETTER_REASON,
+    };
+
+    use super::DeadLetterQueue;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn replay_skips_poison() {
+        let dir = TempDir::new("dead_letter");
+        let mut dlq = DeadLetterQueue::open(&dir.join("dead_letter.qf")).unwrap();
+
+        let exchange = Exchange::new(b"Hello", "Animal", Some("artcoded".into()), HashMap::new());
+        dlq.push(&exchange, "too many deliveries", Some("Nordine"))
+            .unwrap();
+        dlq.push_poison("public", b"garbage", "invalid exchange")
+            .unwrap();
+        assert_eq!(2, dlq.list(|_| true).unwrap().len());
+        assert_eq!(1, dlq.list(|tenant| tenant == "artcoded").unwrap().len());
+        assert!(dlq.drain_replayable(|_| false).unwrap().is_empty());
+
+        let replayed = dlq.drain_replayable(|_| true).unwrap();
+        assert_eq!(1, replayed.len());
+        let (replayed, consumer) = &replayed[0];
+        assert_eq!("Animal", replayed.topic);
+        assert_eq!(Some("Nordine"), consumer.as_deref());
+        assert_ne!(exchange.id(), replayed.id());
+        assert!(replayed.timestamp >= exchange.timestamp);
+        assert!(!replayed.headers.contains_key(HEADER_DEAD_LETTER_REASON));
+        assert!(!replayed.headers.contains_key(HEADER_DEAD_LETTER_CONSUMER));
+        assert_eq!(1, dlq.list(|_| true).unwrap().len());
+    }
+}
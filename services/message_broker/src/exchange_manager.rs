@@ -1,9 +1,45 @@
-use crate::constants::PUB_PERSISTENT_DIR;
-use axum::extract::ws::{Message, WebSocket};
-use futures_util::{stream::SplitSink, SinkExt};
-use mu_rust_message_common::exchange::Exchange;
+use crate::{
+    acl::Grants,
+    constants::{
+        PUB_BATCH_SIZE, PUB_DUPLICATE_SERVICE, PUB_MAX_DELIVERIES, PUB_OUTBOUND_BUFFER_SIZE,
+        PUB_PERSISTENT_DIR, PUB_PUBLISH_BUFFER_SIZE, PUB_SEGMENT_SIZE, PUB_VISIBILITY_TIMEOUT,
+    },
+    dead_letter::DeadLetterQueue,
+    metrics::{
+        Metrics, FAILED_DEAD_LETTER, FAILED_DROPPED, FAILED_EXPIRED, FAILED_POISON, FAILED_SEND,
+    },
+    retention::{is_ttl_expired, ExpiredAction, RetentionPolicies},
+    scheduler::{ScheduledView, Scheduler},
+    segment_log::{decode_name, LogKey, TopicLog},
+    subscribers::{Connection, ConnectionId, DuplicatePolicy, Outbound, Subscribers},
+    subscription_store::{ConsumerId, Cursor, SubscriptionStore, TenantTopic},
+    topic_index::TopicIndex,
+};
+use axum::extract::ws::Message;
+use chrono::{Local, NaiveDateTime};
+use futures_util::Sink;
+use mu_rust_message_common::{
+    exchange::{Exchange, HEADER_DELIVERY_ID, HEADER_DELIVER_AT},
+    topic::TopicPattern,
+    Backpressure, SlowConsumerPolicy, StartPosition, SubscribeOptions, SubscriptionInfo,
+};
+use mu_rust_service_common::PUBLIC_TENANT;
 use queue_file::QueueFile;
-use std::{env::var, error::Error, fmt::Display, path::PathBuf};
+use serde::Serialize;
+use std::{
+    cmp::Reverse,
+    collections::{hash_map::Entry, BinaryHeap, HashMap, HashSet},
+    env::var,
+    error::Error,
+    fmt::Display,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{mpsc::error::TrySendError, Mutex, Notify},
+    time,
+};
 #[derive(Debug)]
 pub struct ExchangeError {
     pub msg: String,
@@ -14,16 +50,225 @@ impl Display for ExchangeError {
     }
 }
 
+/// Logs, cursors and in-flight deliveries of the broker. They are shared
+/// behind a single lock; only the routing of frames to the subscribers is
+/// not, and nothing awaits a socket while holding it.
 #[derive(Debug)]
 pub struct ExchangeManager {
-    subscribers: Vec<Subscriber>,
-    queue: QueueFile,
+    log_dir: PathBuf,
+    subscribers: Subscribers,
+    /// One append-only log per tenant and topic.
+    logs: HashMap<LogKey, TopicLog>,
+    store: SubscriptionStore,
+    indexes: HashMap<String, TopicIndex>,
+    dead_letters: DeadLetterQueue,
+    scheduler: Scheduler,
+    retention: RetentionPolicies,
+    /// Keyed by log and offset, as acknowledged through the delivery id.
+    deliveries: HashMap<(LogKey, u64), PendingDelivery>,
+    group_cursors: HashMap<String, usize>,
+    /// Temporary reply queues by topic, with the service owning them.
+    reply_queues: HashMap<String, String>,
+    /// What each connected service may subscribe to.
+    grants: HashMap<String, Grants>,
+    visibility_timeout: Duration,
+    max_deliveries: u32,
+    batch_size: u64,
+    segment_size: u64,
+    outbound_buffer_size: usize,
+    publish_buffer_size: usize,
+    duplicate_policy: DuplicatePolicy,
+    /// Logs with exchanges to deliver since the last consumption pass.
+    dirty: HashSet<LogKey>,
+    /// Logs to look at again when the visibility timeout of a delivery
+    /// expires, earliest first. Deliveries acknowledged since stay queued.
+    redeliveries: BinaryHeap<Reverse<(Instant, LogKey)>>,
+    /// Logs whose delivery stopped on the full outbound buffer of a service,
+    /// resumed once the buffer has room.
+    held_back: HashMap<String, HashSet<LogKey>>,
+    delivery_trigger: Arc<Notify>,
+    /// Notified when consumers settle exchanges, for the blocked publishers.
+    drained: Arc<Notify>,
+    metrics: Metrics,
 }
+/// A consumer and its subscriptions, as listed by the admin API.
+#[derive(Debug, Serialize)]
+pub struct SubscriptionView {
+    pub consumer: String,
+    pub topics: Vec<TenantTopic>,
+    pub members: Vec<String>,
+}
+
+/// Exchanges retained in a topic log and still pending for each consumer.
+#[derive(Debug, Serialize)]
+pub struct TopicDepth {
+    pub tenant: String,
+    pub topic: String,
+    pub start_offset: u64,
+    pub next_offset: u64,
+    pub pending: HashMap<String, u64>,
+}
+
+/// In-flight deliveries of a log record, keyed by consumer.
+#[derive(Debug)]
+struct PendingDelivery {
+    log: LogKey,
+    offset: u64,
+    delivered: HashMap<ConsumerId, InFlight>,
+    attempts: HashMap<ConsumerId, u32>,
+}
+
 #[derive(Debug)]
-pub struct Subscriber {
+struct InFlight {
+    at: Instant,
     service_id: String,
-    sender: SplitSink<WebSocket, Message>,
-    subscriptions: Vec<String>,
+}
+
+impl PendingDelivery {
+    fn new(log: LogKey, offset: u64) -> PendingDelivery {
+        PendingDelivery {
+            log,
+            offset,
+            delivered: Default::default(),
+            attempts: Default::default(),
+        }
+    }
+
+    fn is_due(&self, consumer: &ConsumerId, now: Instant, visibility_timeout: Duration) -> bool {
+        match self.delivered.get(consumer) {
+            Some(in_flight) => now.duration_since(in_flight.at) >= visibility_timeout,
+            None => true,
+        }
+    }
+
+    /// Remove and return the consumers the exchange was sent to through that service.
+    fn settle(&mut self, service_id: &str) -> Vec<ConsumerId> {
+        let consumers = self
+            .delivered
+            .iter()
+            .filter(|(_, in_flight)| in_flight.service_id == service_id)
+            .map(|(consumer, _)| consumer.clone())
+            .collect::<Vec<_>>();
+        for consumer in consumers.iter() {
+            self.delivered.remove(consumer);
+        }
+        consumers
+    }
+}
+
+fn tenant_topic(topic: &str, options: &SubscribeOptions) -> Result<TenantTopic, ExchangeError> {
+    Ok(TenantTopic {
+        tenant: options
+            .tenant
+            .clone()
+            .unwrap_or_else(|| PUBLIC_TENANT.to_string()),
+        topic: TopicPattern::parse(topic).map_err(to_service_error)?,
+    })
+}
+
+fn subscription_info(topic: &TenantTopic, group: Option<String>) -> SubscriptionInfo {
+    SubscriptionInfo {
+        topic: topic.topic.as_str().to_owned(),
+        tenant: topic.tenant.clone(),
+        group,
+    }
+}
+
+/// Delivery id of the record at that offset of the log.
+fn delivery_id(key: &LogKey, offset: u64) -> String {
+    format!("{}@{offset}", key.path())
+}
+
+fn parse_delivery_id(delivery_id: &str) -> Option<(LogKey, u64)> {
+    let (path, offset) = delivery_id.rsplit_once('@')?;
+    let (tenant, topic) = path.split_once('/')?;
+    Some((
+        LogKey::new(&decode_name(tenant)?, &decode_name(topic)?),
+        offset.parse().ok()?,
+    ))
+}
+
+/// Whether the service consumes for that consumer, itself or one of its groups.
+fn consumes_for(store: &SubscriptionStore, service_id: &str, consumer: &ConsumerId) -> bool {
+    match consumer {
+        ConsumerId::Service(consumer) => consumer == service_id,
+        ConsumerId::Group(_) => store
+            .get(consumer)
+            .map(|subscription| subscription.members.iter().any(|m| m == service_id))
+            .unwrap_or(false),
+    }
+}
+
+/// Round-robin over the connected members of a consumer group allowed to
+/// receive the exchanges of that log.
+fn next_member(
+    members: &[String],
+    grants: &HashMap<String, Grants>,
+    key: &LogKey,
+    excluded: &[String],
+    cursor: &mut usize,
+) -> Option<String> {
+    for _ in 0..members.len() {
+        let member = &members[*cursor % members.len()];
+        *cursor = (*cursor + 1) % members.len();
+        if excluded.contains(member) {
+            continue;
+        }
+        let allowed = grants.get(member).is_some_and(|grants| {
+            grants
+                .check_subscribe(member, &key.topic, &key.tenant)
+                .is_ok()
+        });
+        if allowed {
+            return Some(member.clone());
+        }
+    }
+    None
+}
+
+fn parse_var<T: std::str::FromStr>(key: &str, default: &str) -> Result<T, ExchangeError>
+where
+    T::Err: Error,
+{
+    var(key)
+        .unwrap_or_else(|_| String::from(default))
+        .parse::<T>()
+        .map_err(to_service_error)
+}
+
+/// An exchange is published to a single topic of a named tenant.
+fn check_destination(exchange: &Exchange) -> Result<(), ExchangeError> {
+    if exchange.tenant.as_deref().is_some_and(str::is_empty) {
+        return Err(ExchangeError {
+            msg: "empty tenant".into(),
+        });
+    }
+    if TopicPattern::parse(&exchange.topic)
+        .map_err(to_service_error)?
+        .is_wildcard()
+    {
+        return Err(ExchangeError {
+            msg: format!("cannot publish to wildcard topic {}", exchange.topic),
+        });
+    }
+    Ok(())
+}
+
+fn deliver_at(exchange: &Exchange) -> Result<Option<NaiveDateTime>, ExchangeError> {
+    exchange.deliver_at().map_err(|e| ExchangeError {
+        msg: format!("invalid {HEADER_DELIVER_AT} header: {e}"),
+    })
+}
+
+/// Check what the publisher decides on, for the errors that are its own
+/// rather than the broker's: the destination and the deliver-at header.
+pub fn check_exchange(exchange: &Exchange) -> Result<(), ExchangeError> {
+    if Exchange::is_reply_topic(&exchange.topic) {
+        return Ok(());
+    }
+    check_destination(exchange)?;
+    deliver_at(exchange)?;
+    Ok(())
 }
 
 impl ExchangeManager {
@@ -35,133 +280,1449 @@ impl ExchangeManager {
                     .join("exchange_manager")
                     .join("journal")
             });
+        ExchangeManager::open(&path)
+    }
+
+    /// Open the broker state persisted in that directory.
+    pub fn open(path: &Path) -> Result<ExchangeManager, ExchangeError> {
         if !path.exists() {
-            std::fs::create_dir_all(&path).map_err(to_service_error)?;
+            std::fs::create_dir_all(path).map_err(to_service_error)?;
         }
         if !path.is_dir() {
             return Err(ExchangeError {
                 msg: format!("{path:?} not a directory"),
             });
         }
-        let qf = QueueFile::open(path.join("queue.qf")).map_err(|e| ExchangeError {
-            msg: format!("{e:}"),
-        })?;
-        Ok(Self {
+        let segment_size = parse_var(PUB_SEGMENT_SIZE, "16777216")?;
+        let log_dir = path.join("log");
+        let mut logs = HashMap::new();
+        std::fs::create_dir_all(&log_dir).map_err(to_service_error)?;
+        for tenant_dir in std::fs::read_dir(&log_dir).map_err(to_service_error)? {
+            let tenant_dir = tenant_dir.map_err(to_service_error)?.path();
+            let Some(tenant) = dir_name(&tenant_dir) else {
+                continue;
+            };
+            for topic_dir in std::fs::read_dir(&tenant_dir).map_err(to_service_error)? {
+                let topic_dir = topic_dir.map_err(to_service_error)?.path();
+                if let Some(topic) = dir_name(&topic_dir) {
+                    let log = TopicLog::open(&topic_dir, segment_size)?;
+                    logs.insert(LogKey::new(&tenant, &topic), log);
+                }
+            }
+        }
+        let store = SubscriptionStore::open(&path.join("subscriptions.json"))?;
+        let mut indexes: HashMap<String, TopicIndex> = HashMap::new();
+        for (consumer, subscription) in store.iter() {
+            for TenantTopic { tenant, topic } in subscription.topics.iter() {
+                indexes
+                    .entry(tenant.clone())
+                    .or_default()
+                    .insert(topic, consumer);
+            }
+        }
+        let dead_letters = DeadLetterQueue::open(&path.join("dead_letter.qf"))?;
+        let visibility_timeout = parse_var(PUB_VISIBILITY_TIMEOUT, "30000")?;
+        let mut manager = Self {
+            log_dir,
             subscribers: Default::default(),
-            queue: qf,
-        })
+            logs,
+            store,
+            indexes,
+            dead_letters,
+            scheduler: Scheduler::open(&path.join("scheduled.json"))?,
+            retention: RetentionPolicies::load()?,
+            deliveries: Default::default(),
+            group_cursors: Default::default(),
+            reply_queues: Default::default(),
+            grants: Default::default(),
+            visibility_timeout: Duration::from_millis(visibility_timeout),
+            max_deliveries: parse_var(PUB_MAX_DELIVERIES, "5")?,
+            batch_size: parse_var(PUB_BATCH_SIZE, "100")?,
+            segment_size,
+            outbound_buffer_size: parse_var(PUB_OUTBOUND_BUFFER_SIZE, "1000")?,
+            publish_buffer_size: parse_var(PUB_PUBLISH_BUFFER_SIZE, "1000")?,
+            duplicate_policy: parse_var(PUB_DUPLICATE_SERVICE, "reject")?,
+            dirty: Default::default(),
+            redeliveries: Default::default(),
+            held_back: Default::default(),
+            delivery_trigger: Default::default(),
+            drained: Default::default(),
+            metrics: Metrics::new()?,
+        };
+        manager.migrate_queue_files(path)?;
+        Ok(manager)
     }
 
-    pub fn connect(&mut self, service_id: &str, sender: SplitSink<WebSocket, Message>) {
-        let new_subscriber = Subscriber {
-            service_id: service_id.to_owned(),
-            sender,
-            subscriptions: vec![],
-        };
-        self.subscribers.push(new_subscriber);
+    /// Move the exchanges of the queue files used before the segmented log
+    /// into their topic log, then delete the queue files.
+    fn migrate_queue_files(&mut self, path: &Path) -> Result<(), ExchangeError> {
+        for entry in std::fs::read_dir(path).map_err(to_service_error)? {
+            let file = entry.map_err(to_service_error)?.path();
+            let Some(file_name) = file.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            if !(file_name.starts_with("queue") && file_name.ends_with(".qf")) {
+                continue;
+            }
+            tracing::info!("migrate {file_name} to the topic logs");
+            let mut queue = QueueFile::open(&file).map_err(|e| ExchangeError {
+                msg: format!("{e:}"),
+            })?;
+            for exchange_binary in queue.iter() {
+                match Exchange::deserialize(&exchange_binary) {
+                    Ok(mut exchange) => {
+                        // queued before exchanges had an id
+                        exchange.ensure_id();
+                        let binary = exchange.serialize().map_err(to_service_error)?;
+                        self.append(exchange.tenant.as_deref(), &exchange.topic, &binary)?;
+                    }
+                    Err(e) => {
+                        self.dead_letters.push_poison(
+                            PUBLIC_TENANT,
+                            &exchange_binary,
+                            &e.to_string(),
+                        )?;
+                    }
+                }
+            }
+            drop(queue);
+            std::fs::remove_file(&file).map_err(to_service_error)?;
+        }
+        Ok(())
     }
 
-    pub fn subscribe(&mut self, service_id: &String, subscription: &str) {
-        let existing_agent = self
+    /// Register a new connection of the service, with what it may subscribe
+    /// to, applying the duplicate policy if the service is already
+    /// connected. A rejected connection is sent an error frame and closed.
+    pub fn connect<S>(
+        &mut self,
+        service_id: &str,
+        sender: S,
+        grants: Grants,
+    ) -> Result<ConnectionId, ExchangeError>
+    where
+        S: Sink<Message> + Unpin + Send + 'static,
+        S::Error: Display,
+    {
+        let outbound = Outbound::spawn(
+            service_id,
+            sender,
+            self.outbound_buffer_size,
+            self.delivery_trigger.clone(),
+        );
+        if self.duplicate_policy == DuplicatePolicy::Reject && self.subscribers.contains(service_id)
+        {
+            let error = ExchangeError {
+                msg: format!("{service_id} is already connected"),
+            };
+            outbound.send_error(&error);
+            return Err(error);
+        }
+        let id = self.subscribers.next_id();
+        if let Some(previous) = self
             .subscribers
-            .iter_mut()
-            .find(|s| s.service_id.eq(service_id));
+            .insert(service_id, Connection { id, outbound })
+        {
+            tracing::warn!(
+                "connection {} of {service_id} taken over by connection {id}",
+                previous.id
+            );
+            previous.outbound.send_error(&ExchangeError {
+                msg: format!("taken over by connection {id}"),
+            });
+            // the new connection gets the exchanges in flight on the previous one
+            for delivery in self.deliveries.values_mut() {
+                delivery.settle(service_id);
+            }
+        }
+        self.grants.insert(service_id.to_owned(), grants);
+        self.metrics.subscribers.set(self.subscribers.len() as i64);
+        self.wake_all();
+        Ok(id)
+    }
 
-        if let Some(existing_subscriber) = existing_agent {
-            existing_subscriber
-                .subscriptions
-                .push(subscription.to_uppercase());
-        } else {
-            tracing::info!("{service_id} not connected");
+    /// Capacity of the queue of exchanges a connection published.
+    pub fn publish_buffer_size(&self) -> usize {
+        self.publish_buffer_size
+    }
+
+    /// Connected subscribers, to look them up without locking the manager.
+    pub fn connected(&self) -> Subscribers {
+        self.subscribers.clone()
+    }
+
+    /// Notified whenever exchanges are ready to be delivered.
+    pub fn delivery_trigger(&self) -> Arc<Notify> {
+        self.delivery_trigger.clone()
+    }
+
+    fn wake(&mut self, key: LogKey) {
+        self.dirty.insert(key);
+        self.delivery_trigger.notify_one();
+    }
+
+    fn wake_all(&mut self) {
+        self.dirty.extend(self.logs.keys().cloned());
+        self.delivery_trigger.notify_one();
+    }
+
+    /// Subscribe the service, or the consumer group it joins, to a topic pattern
+    /// of a tenant. Subscribing again to the same topic changes nothing but
+    /// the options. Joining a group takes its other topics as well, so the
+    /// service must be allowed to subscribe to all of them. A new
+    /// subscription only gets the exchanges published from then on, unless
+    /// `options.from` replays the retained ones.
+    pub fn subscribe(
+        &mut self,
+        service_id: &String,
+        subscription: &str,
+        options: &SubscribeOptions,
+    ) -> Result<SubscriptionInfo, ExchangeError> {
+        let topic = tenant_topic(subscription, options)?;
+        let Some(grants) = self.grants.get(service_id) else {
+            return Err(ExchangeError {
+                msg: format!("{service_id} not connected"),
+            });
+        };
+        grants.check_subscribe(service_id, topic.topic.as_str(), &topic.tenant)?;
+        if let Some(group) = &options.group {
+            if let Some(existing) = self.store.get(&ConsumerId::Group(group.to_owned())) {
+                for TenantTopic { tenant, topic } in existing.topics.iter() {
+                    grants.check_subscribe(service_id, topic.as_str(), tenant)?;
+                }
+            }
         }
+        let consumer = match &options.group {
+            Some(group) => {
+                self.store.join_group(group, service_id);
+                ConsumerId::Group(group.to_owned())
+            }
+            None => ConsumerId::Service(service_id.to_owned()),
+        };
+        if let Some(backpressure) = options.backpressure {
+            self.store.set_backpressure(&consumer, backpressure);
+        }
+        let subscribed = self.store.subscribe(&consumer, &topic);
+        if subscribed {
+            self.indexes
+                .entry(topic.tenant.clone())
+                .or_default()
+                .insert(&topic.topic, &consumer);
+        }
+        match options.from {
+            Some(from) => self.replay(&consumer, &topic, from)?,
+            None if subscribed => self.start_at_end(&consumer, &topic),
+            None => {}
+        }
+        self.wake_all();
+        Ok(subscription_info(&topic, options.group.clone()))
     }
-    pub async fn pong(&mut self, service_id: &String) -> Result<(), ExchangeError> {
-        if let Some(subscriber) = self
-            .subscribers
-            .iter_mut()
-            .find(|subscriber| subscriber.service_id.eq(service_id))
-        {
-            return subscriber
-                .sender
-                .send(Message::Pong("Pong!".into()))
-                .await
-                .map_err(to_service_error);
+
+    /// Remove the subscription of the service to a topic pattern. Group
+    /// membership is not scoped to a topic: the service leaves the group when
+    /// that is its only topic, while its last member removes the topic
+    /// instead. Unsubscribing from one of the topics of a group that has
+    /// other members is rejected.
+    pub fn unsubscribe(
+        &mut self,
+        service_id: &str,
+        subscription: &str,
+        options: &SubscribeOptions,
+    ) -> Result<SubscriptionInfo, ExchangeError> {
+        let topic = tenant_topic(subscription, options)?;
+        let consumer = match &options.group {
+            Some(group) => ConsumerId::Group(group.to_owned()),
+            None => ConsumerId::Service(service_id.to_owned()),
+        };
+        let subscribed = self
+            .store
+            .get(&consumer)
+            .map(|s| {
+                s.topics.contains(&topic)
+                    && (options.group.is_none() || s.members.iter().any(|m| m == service_id))
+            })
+            .unwrap_or(false);
+        if !subscribed {
+            return Err(ExchangeError {
+                msg: format!("{service_id} is not subscribed to {subscription}"),
+            });
         }
-        Ok(())
+        let removed = match (&options.group, self.store.get(&consumer)) {
+            (Some(group), Some(group_subscription)) => {
+                let others = group_subscription.members.len() > 1;
+                let topics = group_subscription.topics.len();
+                if others && topics > 1 {
+                    return Err(ExchangeError {
+                        msg: format!(
+                            "{service_id} cannot unsubscribe from {subscription} alone: group {group} has other topics"
+                        ),
+                    });
+                }
+                if topics == 1 {
+                    self.store.leave_group(group, service_id);
+                }
+                // the group keeps its topic while other members consume it
+                !others && self.store.unsubscribe(&consumer, &topic)
+            }
+            _ => self.store.unsubscribe(&consumer, &topic),
+        };
+        if removed {
+            if let Some(index) = self.indexes.get_mut(&topic.tenant) {
+                index.remove(&topic.topic, &consumer);
+            }
+        }
+        // what the service has in flight from those logs goes to the remaining
+        // members of the group, if any
+        self.deliveries.retain(|_, delivery| {
+            if delivery.log.tenant != topic.tenant || !topic.topic.matches(&delivery.log.topic) {
+                return true;
+            }
+            if delivery
+                .delivered
+                .get(&consumer)
+                .is_some_and(|in_flight| in_flight.service_id == service_id)
+            {
+                delivery.delivered.remove(&consumer);
+            }
+            if removed {
+                delivery.attempts.remove(&consumer);
+            }
+            !(delivery.delivered.is_empty() && delivery.attempts.is_empty())
+        });
+        self.wake_all();
+        Ok(subscription_info(&topic, options.group.clone()))
     }
 
-    pub async fn close_connection(&mut self, service_id: &str) -> Result<(), ExchangeError> {
-        let position = self
-            .subscribers
+    /// Subscriptions of the service, and of the groups it is a member of.
+    pub fn list_subscriptions(&self, service_id: &str) -> Vec<SubscriptionInfo> {
+        self.store
+            .iter()
+            .filter_map(|(consumer, subscription)| match consumer {
+                ConsumerId::Service(id) if id == service_id => Some((None, subscription)),
+                ConsumerId::Group(group)
+                    if subscription.members.iter().any(|m| m == service_id) =>
+                {
+                    Some((Some(group.clone()), subscription))
+                }
+                _ => None,
+            })
+            .flat_map(|(group, subscription)| {
+                subscription
+                    .topics
+                    .iter()
+                    .map(move |topic| subscription_info(topic, group.clone()))
+            })
+            .collect()
+    }
+    /// Start the consumer at the end of the logs it did not consume yet that
+    /// the topic brings, so that their retained exchanges are not delivered.
+    fn start_at_end(&mut self, consumer: &ConsumerId, topic: &TenantTopic) {
+        let Some(subscription) = self.store.get(consumer) else {
+            return;
+        };
+        let matches =
+            |t: &TenantTopic, key: &LogKey| t.tenant == key.tenant && t.topic.matches(&key.topic);
+        let new_logs = self
+            .logs
             .iter()
-            .position(|s| s.service_id.eq(service_id));
-        if let Some(position) = position {
-            let mut subscriber = self.subscribers.remove(position);
-            subscriber.sender.close().await.map_err(to_service_error)?;
+            .filter(|(key, _)| {
+                matches(topic, key)
+                    && !subscription.cursors.contains_key(&key.path())
+                    && !subscription
+                        .topics
+                        .iter()
+                        .any(|t| t != topic && matches(t, key))
+            })
+            .map(|(key, log)| (key.path(), log.next_offset()))
+            .collect::<Vec<_>>();
+        for (path, offset) in new_logs {
+            self.store.seek(consumer, &path, offset);
+        }
+    }
+
+    /// Move the cursor of the consumer in every log matching the topic back
+    /// (or forward) to the position. The retained exchanges are then
+    /// delivered in order by the consumption pass, followed by the live ones.
+    fn replay(
+        &mut self,
+        consumer: &ConsumerId,
+        topic: &TenantTopic,
+        from: StartPosition,
+    ) -> Result<(), ExchangeError> {
+        for (key, log) in self.logs.iter() {
+            if key.tenant != topic.tenant || !topic.topic.matches(&key.topic) {
+                continue;
+            }
+            let offset = match from {
+                StartPosition::Offset(offset) => offset.max(log.start_offset()),
+                // publishers stamp the exchanges, so their timestamps are
+                // not ordered by offset
+                StartPosition::Timestamp(timestamp) => log.position(|binary| {
+                    Exchange::deserialize(binary)
+                        .map(|exchange| exchange.timestamp >= timestamp)
+                        .unwrap_or(false)
+                })?,
+            };
+            tracing::info!("replay {} for {consumer} from {offset}", key.path());
+            self.store.seek(consumer, &key.path(), offset);
+            // the replayed exchanges are due right away, even if in flight
+            for delivery in self.deliveries.values_mut() {
+                if &delivery.log == key && delivery.offset >= offset {
+                    delivery.delivered.remove(consumer);
+                    delivery.attempts.remove(consumer);
+                }
+            }
         }
         Ok(())
     }
-    pub async fn consume_queue(&mut self) -> Result<(), ExchangeError> {
-        let mut consumed_messages = vec![];
-        for exchange_binary in self.queue.iter() {
-            let mut unsubscribed = vec![];
-            let exchange = Exchange::deserialize(&exchange_binary).map_err(to_service_error)?;
-            let mut consumed = false;
-            for (index, subscriber) in &mut self.subscribers.iter_mut().enumerate() {
-                if subscriber
-                    .subscriptions
-                    .contains(&exchange.topic.to_uppercase())
+
+    pub fn ack(&mut self, service_id: &str, delivery_id: &str) {
+        let Some((delivery_key, delivery)) = parse_delivery_id(delivery_id).and_then(|key| {
+            self.deliveries
+                .get_mut(&key)
+                .map(|delivery| (key, delivery))
+        }) else {
+            tracing::debug!("{service_id} acked unknown delivery {delivery_id}");
+            return;
+        };
+        let mut consumers = delivery.settle(service_id);
+        if consumers.is_empty() {
+            // late ack, after the visibility timeout expired: settle for the
+            // consumers the service received it for, even if sent again since
+            consumers = delivery
+                .attempts
+                .keys()
+                .filter(|consumer| consumes_for(&self.store, service_id, consumer))
+                .cloned()
+                .collect();
+        }
+        let start_offset = self
+            .logs
+            .get(&delivery.log)
+            .map(|log| log.start_offset())
+            .unwrap_or_default();
+        for consumer in consumers {
+            delivery.delivered.remove(&consumer);
+            delivery.attempts.remove(&consumer);
+            self.store.ack(
+                &consumer,
+                &delivery.log.path(),
+                delivery.offset,
+                start_offset,
+            );
+        }
+        let key = delivery.log.clone();
+        if delivery.attempts.is_empty() && delivery.delivered.is_empty() {
+            self.deliveries.remove(&delivery_key);
+        }
+        self.wake(key);
+        self.drained.notify_waiters();
+    }
+
+    pub fn nack(&mut self, service_id: &str, delivery_id: &str) {
+        let delivery = parse_delivery_id(delivery_id).and_then(|key| self.deliveries.get_mut(&key));
+        if let Some(delivery) = delivery {
+            // make it due for redelivery on the next consumption pass
+            delivery.settle(service_id);
+            let key = delivery.log.clone();
+            self.wake(key);
+        } else {
+            tracing::debug!("{service_id} nacked unknown delivery {delivery_id}");
+        }
+    }
+
+    /// Forget the connection of the service. Its socket is closed once the
+    /// frames already queued for it are written.
+    pub fn close_connection(&mut self, service_id: &str) {
+        self.remove_connection(service_id, None);
+    }
+
+    /// Forget that connection, unless another one took it over.
+    pub fn disconnect(&mut self, service_id: &str, id: ConnectionId) {
+        self.remove_connection(service_id, Some(id));
+    }
+
+    fn remove_connection(&mut self, service_id: &str, id: Option<ConnectionId>) {
+        if self.subscribers.remove(service_id, id).is_some() {
+            // unacked in-flight exchanges are redelivered as soon as the service reconnects
+            for delivery in self.deliveries.values_mut() {
+                delivery.settle(service_id);
+            }
+            self.reply_queues.retain(|_, owner| owner != service_id);
+            self.grants.remove(service_id);
+            self.metrics.subscribers.set(self.subscribers.len() as i64);
+            self.drained.notify_waiters();
+        }
+    }
+
+    /// When `consume_queue` is next due: the earliest visibility timeout of
+    /// the deliveries or scheduled exchange.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let redelivery = self.redeliveries.peek().map(|Reverse((at, _))| *at);
+        let scheduled = self.scheduler.next_due().map(|deliver_at| {
+            let delay = deliver_at - Local::now().naive_local();
+            Instant::now() + delay.to_std().unwrap_or_default()
+        });
+        redelivery.into_iter().chain(scheduled).min()
+    }
+
+    /// Look at the logs with deliveries whose visibility timeout expired.
+    pub fn consume_queue(&mut self) {
+        let now = Instant::now();
+        while let Some(Reverse((at, _))) = self.redeliveries.peek() {
+            if *at > now {
+                break;
+            }
+            if let Some(Reverse((_, key))) = self.redeliveries.pop() {
+                self.dirty.insert(key);
+            }
+        }
+        self.consume_pending();
+    }
+
+    /// Deliver the logs woken up by a publication, an acknowledgement, a new
+    /// subscription or an outbound buffer that has room again.
+    pub fn consume_pending(&mut self) {
+        let (subscribers, dirty) = (&self.subscribers, &mut self.dirty);
+        self.held_back.retain(|service_id, keys| {
+            if subscribers.get(service_id).is_some_and(|o| o.is_full()) {
+                return true;
+            }
+            dirty.extend(keys.drain());
+            false
+        });
+        for key in std::mem::take(&mut self.dirty) {
+            // a failing log must not prevent the others from being consumed
+            if let Err(e) = self.consume_log(&key) {
+                tracing::error!("could not consume log {}: {e}", key.path());
+            }
+        }
+    }
+
+    /// Deliver the records of a topic log following each consumer cursor. At
+    /// most `batch_size` records past the committed offset are looked at per
+    /// consumer, which also bounds the exchanges in flight.
+    fn consume_log(&mut self, key: &LogKey) -> Result<(), ExchangeError> {
+        let Some(consumers) = self.indexes.get(&key.tenant).map(|i| i.matches(&key.topic)) else {
+            return Ok(());
+        };
+        let Some(log) = self.logs.get(key) else {
+            return Ok(());
+        };
+        let log_path = key.path();
+        let start_offset = log.start_offset();
+        let end_offset = log.next_offset();
+        let labels = [key.tenant.as_str(), key.topic.as_str()];
+        self.metrics
+            .queue_size
+            .with_label_values(&labels)
+            .set((end_offset - start_offset) as i64);
+        let now = Instant::now();
+        let wall_clock = Local::now().naive_local();
+        let on_expired = self
+            .retention
+            .policy_for(&key.topic)
+            .map(|policy| policy.on_expired)
+            .unwrap_or_default();
+        let mut records: HashMap<u64, Option<(Exchange, Vec<u8>)>> = HashMap::new();
+        let mut delivery_key = (key.clone(), 0);
+        let mut settled = vec![];
+        let mut unsubscribed: Vec<String> = vec![];
+        for consumer in consumers.iter() {
+            let Some(subscription) = self.store.get(consumer) else {
+                continue;
+            };
+            let cursor = self.store.cursor(consumer, &log_path, start_offset);
+            let until = end_offset.min(cursor.committed + self.batch_size);
+            for offset in cursor.committed..until {
+                if cursor.is_acked(offset) {
+                    continue;
+                }
+                // records in flight are only read again once due
+                delivery_key.1 = offset;
+                if self
+                    .deliveries
+                    .get(&delivery_key)
+                    .is_some_and(|d| !d.is_due(consumer, now, self.visibility_timeout))
                 {
-                    tracing::info!("send binary message to {}", subscriber.service_id);
-                    if let Err(e) = subscriber
-                        .sender
-                        .send(Message::Binary(exchange_binary.to_vec()))
-                        .await
-                    {
-                        tracing::error!("error {e} for subscriber {}", subscriber.service_id);
-                        unsubscribed.push(index);
-                    } else {
-                        consumed = true;
+                    continue;
+                }
+                let record = match records.entry(offset) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => {
+                        let record = match log.read(offset)? {
+                            Some(binary) => match Exchange::deserialize(&binary) {
+                                Ok(mut exchange) => {
+                                    exchange.headers.insert(
+                                        HEADER_DELIVERY_ID.into(),
+                                        delivery_id(key, offset),
+                                    );
+                                    let binary = exchange.serialize().map_err(to_service_error)?;
+                                    Some((exchange, binary))
+                                }
+                                Err(e) => {
+                                    tracing::error!("poison record {offset} in {log_path}: {e}");
+                                    self.metrics.fail(&key.tenant, &key.topic, FAILED_POISON);
+                                    self.dead_letters.push_poison(
+                                        &key.tenant,
+                                        &binary,
+                                        &e.to_string(),
+                                    )?;
+                                    None
+                                }
+                            },
+                            None => None,
+                        };
+                        entry.insert(record)
+                    }
+                };
+                let Some((exchange, exchange_binary)) = record else {
+                    settled.push((consumer.clone(), offset));
+                    continue;
+                };
+                if is_ttl_expired(exchange, wall_clock) {
+                    tracing::debug!("exchange {} expired", exchange.id());
+                    self.metrics.fail(&key.tenant, &key.topic, FAILED_EXPIRED);
+                    if on_expired == ExpiredAction::DeadLetter {
+                        self.dead_letters
+                            .push(exchange, "expired", Some(&consumer.to_string()))?;
+                    }
+                    settled.push((consumer.clone(), offset));
+                    continue;
+                }
+                let delivery = self
+                    .deliveries
+                    .entry(delivery_key.clone())
+                    .or_insert_with(|| PendingDelivery::new(key.clone(), offset));
+                let attempts = delivery.attempts.get(consumer).copied().unwrap_or(0);
+                if attempts >= self.max_deliveries {
+                    tracing::warn!("dead-letter {} for {consumer}", exchange.id());
+                    self.metrics
+                        .fail(&key.tenant, &key.topic, FAILED_DEAD_LETTER);
+                    self.dead_letters.push(
+                        exchange,
+                        &format!("not acknowledged after {attempts} deliveries"),
+                        Some(&consumer.to_string()),
+                    )?;
+                    delivery.delivered.remove(consumer);
+                    delivery.attempts.remove(consumer);
+                    settled.push((consumer.clone(), offset));
+                    continue;
+                }
+                let service_id = match consumer {
+                    ConsumerId::Service(service_id) => service_id.clone(),
+                    ConsumerId::Group(group) => {
+                        let cursor = self.group_cursors.entry(group.clone()).or_default();
+                        match next_member(
+                            &subscription.members,
+                            &self.grants,
+                            key,
+                            &unsubscribed,
+                            cursor,
+                        ) {
+                            Some(member) => member,
+                            None => break,
+                        }
                     }
+                };
+                if unsubscribed.contains(&service_id) {
+                    break;
                 }
+                // durable consumers that are not connected keep their cursor
+                let Some(outbound) = self.subscribers.get(&service_id) else {
+                    break;
+                };
+                tracing::info!("send binary message to {service_id}");
+                match outbound.try_send(Message::Binary(exchange_binary.clone())) {
+                    Ok(()) => {}
+                    // delivered again once the subscriber catches up
+                    Err(TrySendError::Full(_)) => {
+                        tracing::debug!("outbound buffer of {service_id} is full");
+                        self.held_back
+                            .entry(service_id)
+                            .or_default()
+                            .insert(key.clone());
+                        break;
+                    }
+                    Err(TrySendError::Closed(_)) => {
+                        tracing::error!("subscriber {service_id} is disconnected");
+                        self.metrics.fail(&key.tenant, &key.topic, FAILED_SEND);
+                        unsubscribed.push(service_id);
+                        break;
+                    }
+                }
+                *delivery.attempts.entry(consumer.clone()).or_default() += 1;
+                self.metrics.delivered.with_label_values(&labels).inc();
+                let latency = Local::now().naive_local() - exchange.timestamp;
+                self.metrics
+                    .delivery_latency
+                    .with_label_values(&labels)
+                    .observe(latency.num_milliseconds().max(0) as f64 / 1000.0);
+                delivery.delivered.insert(
+                    consumer.clone(),
+                    InFlight {
+                        at: now,
+                        service_id,
+                    },
+                );
+                self.redeliveries
+                    .push(Reverse((now + self.visibility_timeout, key.clone())));
             }
-            if consumed {
-                consumed_messages.push(exchange_binary);
+        }
+        for (consumer, offset) in settled {
+            self.store.ack(&consumer, &log_path, offset, start_offset);
+        }
+        for service_id in unsubscribed {
+            self.close_connection(&service_id);
+        }
+        Ok(())
+    }
+
+    /// Apply the retention policies. Topics with a policy keep the settled
+    /// exchanges so that they can be replayed; the others only keep the
+    /// records not yet settled by every consumer of the topic.
+    pub fn compact(&mut self) -> Result<(), ExchangeError> {
+        let wall_clock = Local::now().naive_local();
+        for (key, log) in self.logs.iter_mut() {
+            let (retained_from, action) =
+                self.retention.retained_from(&key.topic, log, wall_clock)?;
+            let cursors = self
+                .indexes
+                .get(&key.tenant)
+                .map(|index| index.matches(&key.topic))
+                .unwrap_or_default()
+                .into_iter()
+                .map(|c| {
+                    let cursor = self.store.cursor(&c, &key.path(), log.start_offset());
+                    (c, cursor)
+                })
+                .collect::<Vec<_>>();
+            if action == ExpiredAction::DeadLetter {
+                // dead-lettered for each consumer that did not settle it yet,
+                // or once when nobody consumes the topic
+                for offset in log.start_offset()..retained_from {
+                    let unsettled = cursors
+                        .iter()
+                        .filter(|(_, cursor)| !cursor.is_acked(offset))
+                        .map(|(consumer, _)| Some(consumer.to_string()))
+                        .collect::<Vec<_>>();
+                    let failed_by = if cursors.is_empty() {
+                        vec![None]
+                    } else {
+                        unsettled
+                    };
+                    if failed_by.is_empty() {
+                        continue;
+                    }
+                    if let Some(exchange) = log
+                        .read(offset)?
+                        .and_then(|binary| Exchange::deserialize(&binary).ok())
+                    {
+                        for consumer in failed_by {
+                            self.dead_letters
+                                .push(&exchange, "expired", consumer.as_deref())?;
+                        }
+                    }
+                }
             }
-            for position in unsubscribed {
-                let mut subscriber = self.subscribers.remove(position);
-                subscriber.sender.close().await.map_err(to_service_error)?;
+            if self.retention.policy_for(&key.topic).is_some() {
+                log.truncate_before(retained_from)?;
+                continue;
             }
+            let consumed = cursors
+                .iter()
+                .map(|(_, cursor)| cursor.committed)
+                .min()
+                .unwrap_or_default();
+            log.truncate_before(retained_from.max(consumed))?;
         }
-        let stored = self
-            .queue
-            .iter()
-            .filter(|ex| !consumed_messages.contains(ex))
-            .map(Vec::from)
-            .collect::<Vec<_>>();
-        self.queue.clear().map_err(to_service_error)?;
-        self.queue.add_n(stored).map_err(to_service_error)?;
+        for (key, log) in self.logs.iter() {
+            self.metrics
+                .queue_size
+                .with_label_values(&[&key.tenant, &key.topic])
+                .set((log.next_offset() - log.start_offset()) as i64);
+        }
+        let logs = &self.logs;
+        self.deliveries.retain(|_, delivery| {
+            logs.get(&delivery.log)
+                .map(|log| delivery.offset >= log.start_offset())
+                .unwrap_or(false)
+        });
         Ok(())
     }
 
     pub fn sync_queue_file(&mut self) -> Result<(), ExchangeError> {
         tracing::trace!("sync queue file...");
-        self.queue.sync_all().map_err(to_service_error)?;
+        for log in self.logs.values_mut() {
+            log.sync()?;
+        }
+        self.dead_letters.sync_all()?;
+        self.store.save()?;
+        self.scheduler.save()?;
         Ok(())
     }
 
-    pub async fn publish(&mut self, exchange_binary: Vec<u8>) -> Result<(), ExchangeError> {
-        self.queue.add(&exchange_binary).map_err(to_service_error)?;
+    pub fn subscriptions(&self) -> Vec<SubscriptionView> {
+        self.store
+            .iter()
+            .map(|(consumer, subscription)| SubscriptionView {
+                consumer: consumer.to_string(),
+                topics: subscription.topics.clone(),
+                members: subscription.members.clone(),
+            })
+            .collect()
+    }
+
+    pub fn topic_depths(&self) -> Vec<TopicDepth> {
+        self.logs
+            .iter()
+            .map(|(key, log)| {
+                let pending = self
+                    .indexes
+                    .get(&key.tenant)
+                    .map(|index| index.matches(&key.topic))
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|consumer| {
+                        let cursor = self
+                            .store
+                            .cursor(&consumer, &key.path(), log.start_offset());
+                        (consumer.to_string(), cursor.pending(log.next_offset()))
+                    })
+                    .collect();
+                TopicDepth {
+                    tenant: key.tenant.clone(),
+                    topic: key.topic.clone(),
+                    start_offset: log.start_offset(),
+                    next_offset: log.next_offset(),
+                    pending,
+                }
+            })
+            .collect()
+    }
+
+    /// Append the exchange to its topic log, or hold it back until the time
+    /// of its deliver-at header. Returns the log when one of its consumers
+    /// blocks the publishers, to wait for with `wait_for_consumers`.
+    pub fn publish(&mut self, exchange_binary: Vec<u8>) -> Result<Option<LogKey>, ExchangeError> {
+        let mut exchange = Exchange::deserialize(&exchange_binary).map_err(to_service_error)?;
+        let exchange_binary = if exchange.ensure_id() {
+            exchange.serialize().map_err(to_service_error)?
+        } else {
+            exchange_binary
+        };
+        if Exchange::is_reply_topic(&exchange.topic) {
+            self.send_reply(&exchange, exchange_binary)?;
+            return Ok(None);
+        }
+        check_destination(&exchange)?;
+        if let Some(deliver_at) = deliver_at(&exchange)? {
+            if deliver_at > Local::now().naive_local() {
+                tracing::debug!("hold {} until {deliver_at}", exchange.id());
+                self.scheduler.hold(exchange, deliver_at)?;
+                // for the consumer task to sleep until then
+                self.delivery_trigger.notify_one();
+                return Ok(None);
+            }
+        }
+        self.publish_exchange(&exchange, &exchange_binary)
+    }
+
+    fn publish_exchange(
+        &mut self,
+        exchange: &Exchange,
+        exchange_binary: &[u8],
+    ) -> Result<Option<LogKey>, ExchangeError> {
+        check_destination(exchange)?;
+        self.append(exchange.tenant.as_deref(), &exchange.topic, exchange_binary)?;
+        let key = LogKey::new(
+            exchange.tenant.as_deref().unwrap_or(PUBLIC_TENANT),
+            &exchange.topic,
+        );
+        self.metrics
+            .published
+            .with_label_values(&[&key.tenant, &key.topic])
+            .inc();
+        let throttled = self.apply_backpressure(&key);
+        self.wake(key.clone());
+        Ok(throttled.then_some(key))
+    }
+
+    /// Publish the scheduled exchanges that are due. Nobody waits for them,
+    /// even if a consumer blocks the publishers.
+    pub fn publish_due(&mut self) {
+        for exchange in self.scheduler.take_due(Local::now().naive_local()) {
+            if let Err(e) = exchange
+                .serialize()
+                .map_err(to_service_error)
+                .and_then(|binary| self.publish_exchange(&exchange, &binary))
+            {
+                tracing::error!(
+                    "could not publish scheduled exchange {}: {e}",
+                    exchange.id()
+                );
+            }
+        }
+    }
+
+    /// Publish a copy of the exchange at every occurrence of the cron expression.
+    pub fn schedule(&mut self, exchange: Exchange, cron: &str) -> Result<String, ExchangeError> {
+        check_destination(&exchange)?;
+        let id = self.scheduler.repeat(exchange, cron)?;
+        self.delivery_trigger.notify_one();
+        Ok(id)
+    }
+
+    pub fn cancel_schedule(&mut self, id: &str) -> bool {
+        self.scheduler.cancel(id)
+    }
+
+    pub fn schedules(&self) -> Vec<ScheduledView> {
+        self.scheduler.list()
+    }
+
+    /// Connected services consuming for the consumer.
+    fn connected_services(&self, consumer: &ConsumerId) -> Vec<String> {
+        match consumer {
+            ConsumerId::Service(service_id) => vec![service_id.clone()],
+            ConsumerId::Group(_) => self
+                .store
+                .get(consumer)
+                .map(|subscription| subscription.members.clone())
+                .unwrap_or_default(),
+        }
+        .into_iter()
+        .filter(|service_id| self.subscribers.contains(service_id))
+        .collect()
+    }
+
+    /// Consumers of the log with more exchanges pending than their buffer.
+    fn lagging(&self, key: &LogKey) -> Vec<(ConsumerId, Backpressure, Cursor)> {
+        let Some(log) = self.logs.get(key) else {
+            return vec![];
+        };
+        self.indexes
+            .get(&key.tenant)
+            .map(|index| index.matches(&key.topic))
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|consumer| {
+                let backpressure = self.store.get(&consumer)?.backpressure?;
+                let cursor = self
+                    .store
+                    .cursor(&consumer, &key.path(), log.start_offset());
+                (cursor.pending(log.next_offset()) > backpressure.buffer).then_some((
+                    consumer,
+                    backpressure,
+                    cursor,
+                ))
+            })
+            .collect()
+    }
+
+    /// Apply the slow consumer policies after an exchange was appended to
+    /// the log. Returns whether the publisher has to wait.
+    fn apply_backpressure(&mut self, key: &LogKey) -> bool {
+        let Some(log) = self.logs.get(key) else {
+            return false;
+        };
+        let (start_offset, end_offset) = (log.start_offset(), log.next_offset());
+        let log_path = key.path();
+        let mut throttled = false;
+        for (consumer, Backpressure { buffer, policy }, cursor) in self.lagging(key) {
+            let pending = cursor.pending(end_offset);
+            match policy {
+                // a durable consumer that is gone must not block the publishers
+                SlowConsumerPolicy::Block => {
+                    if !self.connected_services(&consumer).is_empty() {
+                        tracing::warn!(
+                            "{consumer} has {pending} exchanges pending in {log_path}, block publishers"
+                        );
+                        throttled = true;
+                    }
+                }
+                SlowConsumerPolicy::DropOldest => {
+                    let mut dropped = 0;
+                    let mut offset = cursor.committed;
+                    while pending - dropped > buffer && offset < end_offset {
+                        if !cursor.is_acked(offset) {
+                            self.store.ack(&consumer, &log_path, offset, start_offset);
+                            self.metrics.fail(&key.tenant, &key.topic, FAILED_DROPPED);
+                            dropped += 1;
+                        }
+                        offset += 1;
+                    }
+                    tracing::warn!(
+                        "{consumer} has {pending} exchanges pending in {log_path}, drop the {dropped} oldest"
+                    );
+                }
+                SlowConsumerPolicy::DropNewest => {
+                    self.store
+                        .ack(&consumer, &log_path, end_offset - 1, start_offset);
+                    self.metrics.fail(&key.tenant, &key.topic, FAILED_DROPPED);
+                    tracing::warn!(
+                        "{consumer} has {pending} exchanges pending in {log_path}, drop the newest"
+                    );
+                }
+                SlowConsumerPolicy::Disconnect => {
+                    let error = ExchangeError {
+                        msg: format!("{pending} exchanges pending in {log_path}, too slow"),
+                    };
+                    for service_id in self.connected_services(&consumer) {
+                        tracing::warn!("disconnect slow consumer {service_id}: {error}");
+                        if let Some(outbound) = self.subscribers.get(&service_id) {
+                            outbound.send_error(&error);
+                        }
+                        self.close_connection(&service_id);
+                    }
+                }
+            }
+        }
+        throttled
+    }
+
+    fn is_throttled(&self, key: &LogKey) -> bool {
+        self.lagging(key).iter().any(|(consumer, backpressure, _)| {
+            backpressure.policy == SlowConsumerPolicy::Block
+                && !self.connected_services(consumer).is_empty()
+        })
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.clone()
+    }
+
+    pub fn open_reply_queue(&mut self, service_id: &str, topic: &str) -> Result<(), ExchangeError> {
+        let pattern = TopicPattern::parse(topic).map_err(to_service_error)?;
+        if pattern.is_wildcard() || !Exchange::is_reply_topic(pattern.as_str()) {
+            return Err(ExchangeError {
+                msg: format!("{topic} is not a reply topic"),
+            });
+        }
+        match self.reply_queues.entry(pattern.as_str().to_owned()) {
+            Entry::Occupied(entry) if entry.get() != service_id => Err(ExchangeError {
+                msg: format!("reply queue {topic} is owned by another service"),
+            }),
+            entry => {
+                entry.or_insert_with(|| service_id.to_owned());
+                Ok(())
+            }
+        }
+    }
+
+    /// Replies go straight to the requester, without being stored: they are
+    /// dropped when the requester is gone.
+    fn send_reply(
+        &mut self,
+        exchange: &Exchange,
+        exchange_binary: Vec<u8>,
+    ) -> Result<(), ExchangeError> {
+        let Some(service_id) = self.reply_queues.get(&exchange.topic.to_uppercase()) else {
+            tracing::debug!("no reply queue {}, drop {}", exchange.topic, exchange.id());
+            return Ok(());
+        };
+        if let Some(outbound) = self.subscribers.get(service_id) {
+            outbound
+                .try_send(Message::Binary(exchange_binary))
+                .map_err(to_service_error)?;
+        }
         Ok(())
     }
+
+    /// The dead letters of the tenants that pass the filter.
+    pub fn inspect_dead_letters(
+        &mut self,
+        tenant_filter: impl Fn(&str) -> bool,
+    ) -> Result<Vec<Vec<u8>>, ExchangeError> {
+        self.dead_letters.list(tenant_filter)
+    }
+
+    /// Move the replayable dead letters of the tenants that pass the filter
+    /// back to the log of their topic. A dead letter is only delivered again
+    /// to the consumer that failed it, when recorded.
+    pub fn replay_dead_letters(
+        &mut self,
+        tenant_filter: impl Fn(&str) -> bool,
+    ) -> Result<usize, ExchangeError> {
+        let replays = self.dead_letters.drain_replayable(tenant_filter)?;
+        let count = replays.len();
+        for (exchange, failed_by) in replays {
+            let binary = exchange.serialize().map_err(to_service_error)?;
+            let offset = self.append(exchange.tenant.as_deref(), &exchange.topic, &binary)?;
+            let Some(failed_by) = failed_by else {
+                continue;
+            };
+            let key = LogKey::new(
+                exchange.tenant.as_deref().unwrap_or(PUBLIC_TENANT),
+                &exchange.topic,
+            );
+            let start_offset = self
+                .logs
+                .get(&key)
+                .map(|log| log.start_offset())
+                .unwrap_or_default();
+            let others = self
+                .indexes
+                .get(&key.tenant)
+                .map(|index| index.matches(&key.topic))
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|consumer| consumer.to_string() != failed_by);
+            for consumer in others {
+                self.store.ack(&consumer, &key.path(), offset, start_offset);
+            }
+        }
+        self.wake_all();
+        Ok(count)
+    }
+
+    fn append(
+        &mut self,
+        tenant: Option<&str>,
+        topic: &str,
+        exchange_binary: &[u8],
+    ) -> Result<u64, ExchangeError> {
+        let key = LogKey::new(tenant.unwrap_or(PUBLIC_TENANT), topic);
+        let log = match self.logs.entry(key) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let log =
+                    TopicLog::open(&self.log_dir.join(entry.key().path()), self.segment_size)?;
+                entry.insert(log)
+            }
+        };
+        log.append(exchange_binary)
+    }
+}
+
+/// Decoded name of a log directory. Other directories are skipped, with a
+/// warning.
+fn dir_name(dir: &Path) -> Option<String> {
+    let name = dir
+        .file_name()
+        .and_then(|n| n.to_str())
+        .and_then(decode_name);
+    if name.is_none() {
+        tracing::warn!("skip {dir:?}, not the directory of a topic log");
+    }
+    name
+}
+
+/// Hold a publisher back until the consumers blocking the log catch up.
+pub async fn wait_for_consumers(state: &Mutex<ExchangeManager>, key: &LogKey) {
+    loop {
+        let drained = {
+            let em = state.lock().await;
+            if !em.is_throttled(key) {
+                return;
+            }
+            em.drained.clone()
+        };
+        // acknowledgements wake the publisher up, the timeout covers the rest
+        let _ = time::timeout(Duration::from_millis(100), drained.notified()).await;
+    }
 }
 
+pub fn to_service_error(e: impl Error) -> ExchangeError {
+    ExchangeError { msg: e.to_string() }
+}
+
+impl Error for ExchangeError {}
+
 #[cfg(test)]
 mod test {
 
-    use std::collections::HashMap;
+    use std::{
+        collections::HashMap,
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use futures_util::sink::drain;
+    use mu_rust_message_common::{
+        exchange::Exchange, StartPosition, SubscribeOptions, TextMessage,
+    };
+
+    use super::{
+        check_destination, delivery_id, parse_delivery_id, ExchangeManager, InFlight,
+        PendingDelivery,
+    };
+    use crate::{
+        acl::{AccessControl, Grants},
+        segment_log::LogKey,
+        subscribers::DuplicatePolicy,
+        subscription_store::ConsumerId,
+        test_util::TempDir,
+    };
+
+    #[test]
+    fn redeliver_after_visibility_timeout() {
+        let timeout = Duration::from_millis(100);
+        let now = Instant::now();
+        let nordine = ConsumerId::Service("Nordine".into());
+        let workers = ConsumerId::Group("workers".into());
+        let mut delivery = PendingDelivery::new(LogKey::new("public", "Animal"), 0);
+        assert!(delivery.is_due(&nordine, now, timeout));
+        delivery.delivered.insert(
+            nordine.clone(),
+            InFlight {
+                at: now,
+                service_id: "Nordine".into(),
+            },
+        );
+        delivery.delivered.insert(
+            workers.clone(),
+            InFlight {
+                at: now,
+                service_id: "worker-1".into(),
+            },
+        );
+        assert!(!delivery.is_due(&nordine, now, timeout));
+        assert!(delivery.is_due(&nordine, now + timeout, timeout));
+        assert!(delivery.is_due(&ConsumerId::Service("Artcoded".into()), now, timeout));
+
+        assert_eq!(vec![workers.clone()], delivery.settle("worker-1"));
+        assert!(delivery.is_due(&workers, now, timeout));
+    }
+
+    #[tokio::test]
+    async fn late_ack_from_group_member() {
+        let dir = TempDir::new("exchange_manager");
+        let mut em = ExchangeManager::open(&dir).unwrap();
+        let workers = ConsumerId::Group("workers".into());
+        let options = SubscribeOptions {
+            group: Some("workers".into()),
+            ..Default::default()
+        };
+        for member in ["worker-1", "worker-2"] {
+            em.connect(member, drain(), Default::default()).unwrap();
+            em.subscribe(&member.to_string(), "Animal", &options)
+                .unwrap();
+        }
+        em.visibility_timeout = Duration::ZERO;
+        let exchange = Exchange::new(b"cat", "Animal", None, HashMap::new());
+        em.publish(exchange.serialize().unwrap()).unwrap();
+        em.consume_pending();
+
+        // sent again to the other member once the visibility timeout expired
+        let key = (LogKey::new("public", "Animal"), 0);
+        let first = em.deliveries[&key].delivered[&workers].service_id.clone();
+        em.consume_queue();
+        assert_ne!(first, em.deliveries[&key].delivered[&workers].service_id);
+
+        em.ack(&first, &delivery_id(&key.0, key.1));
+        assert!(em.store.cursor(&workers, &key.0.path(), 0).is_acked(0));
+        assert!(!em.deliveries.contains_key(&key));
+    }
+
+    #[tokio::test]
+    async fn isolate_tenants() {
+        let dir = TempDir::new("exchange_manager");
+        let mut em = ExchangeManager::open(&dir).unwrap();
+        let artcoded = SubscribeOptions {
+            tenant: Some("artcoded".into()),
+            ..Default::default()
+        };
+        for (service_id, options) in [("Nordine", artcoded), ("Marie", Default::default())] {
+            em.connect(service_id, drain(), Default::default()).unwrap();
+            em.subscribe(&service_id.to_string(), "Animal", &options)
+                .unwrap();
+        }
+        assert_eq!("public", em.list_subscriptions("Marie")[0].tenant);
+        for tenant in [Some("artcoded"), Some("other"), None] {
+            let exchange =
+                Exchange::new(b"cat", "Animal", tenant.map(String::from), HashMap::new());
+            em.publish(exchange.serialize().unwrap()).unwrap();
+        }
+        em.consume_pending();
+
+        let mut received =
+            em.deliveries
+                .values()
+                .flat_map(|delivery| {
+                    delivery.delivered.values().map(|in_flight| {
+                        (delivery.log.tenant.as_str(), in_flight.service_id.as_str())
+                    })
+                })
+                .collect::<Vec<_>>();
+        received.sort();
+        assert_eq!(vec![("artcoded", "Nordine"), ("public", "Marie")], received);
+    }
+
+    #[tokio::test]
+    async fn duplicate_connections() {
+        let dir = TempDir::new("exchange_manager");
+        let mut em = ExchangeManager::open(&dir).unwrap();
+        em.duplicate_policy = DuplicatePolicy::Reject;
+        let first = em.connect("Nordine", drain(), Default::default()).unwrap();
+        assert!(em.connect("Nordine", drain(), Default::default()).is_err());
+        em.disconnect("Nordine", first);
+        let second = em.connect("Nordine", drain(), Default::default()).unwrap();
+
+        em.duplicate_policy = DuplicatePolicy::TakeOver;
+        let third = em.connect("Nordine", drain(), Default::default()).unwrap();
+        // the connection taken over is gone, closing it changes nothing
+        em.disconnect("Nordine", second);
+        assert!(em.subscribers.connection("Nordine", third).is_some());
+    }
+
+    #[tokio::test]
+    async fn ack_by_delivery_id() {
+        let dir = TempDir::new("exchange_manager");
+        let mut em = ExchangeManager::open(&dir).unwrap();
+        let service_id = "Nordine".to_string();
+        em.connect(&service_id, drain(), Default::default())
+            .unwrap();
+        em.subscribe(&service_id, "Animal", &Default::default())
+            .unwrap();
+        // publishers choose the message id, so it may well be reused
+        let exchange = Exchange::new(b"cat", "Animal", None, HashMap::new());
+        for _ in 0..2 {
+            em.publish(exchange.serialize().unwrap()).unwrap();
+        }
+        em.consume_pending();
+        let key = LogKey::new("public", "Animal");
+        assert_eq!(2, em.deliveries.len());
+
+        em.ack(&service_id, &delivery_id(&key, 1));
+        let cursor = em
+            .store
+            .cursor(&ConsumerId::Service(service_id), &key.path(), 0);
+        assert!(!cursor.is_acked(0));
+        assert!(cursor.is_acked(1));
+        assert_eq!(
+            Some((LogKey::new("..", "a/b@c"), 3)),
+            parse_delivery_id(&delivery_id(&LogKey::new("..", "a/b@c"), 3))
+        );
+    }
+
+    #[tokio::test]
+    async fn replay_is_opt_in() {
+        let dir = TempDir::new("exchange_manager");
+        let mut em = ExchangeManager::open(&dir).unwrap();
+        let exchange = Exchange::new(b"cat", "Animal", None, HashMap::new());
+        em.publish(exchange.serialize().unwrap()).unwrap();
+        let key = LogKey::new("public", "Animal");
+        for (service_id, from) in [("Nordine", None), ("Marie", Some(StartPosition::Offset(0)))] {
+            em.connect(service_id, drain(), Default::default()).unwrap();
+            let options = SubscribeOptions {
+                from,
+                ..Default::default()
+            };
+            em.subscribe(&service_id.to_string(), "Animal", &options)
+                .unwrap();
+        }
+        let committed = |em: &ExchangeManager, service_id: &str| {
+            em.store
+                .cursor(&ConsumerId::Service(service_id.into()), &key.path(), 0)
+                .committed
+        };
+        assert_eq!(1, committed(&em, "Nordine"));
+        assert_eq!(0, committed(&em, "Marie"));
+    }
+
+    #[tokio::test]
+    async fn unsubscribe_from_group() {
+        let dir = TempDir::new("exchange_manager");
+        let mut em = ExchangeManager::open(&dir).unwrap();
+        let workers = ConsumerId::Group("workers".into());
+        let options = SubscribeOptions {
+            group: Some("workers".into()),
+            ..Default::default()
+        };
+        for member in ["worker-1", "worker-2"] {
+            em.connect(member, drain(), Default::default()).unwrap();
+            em.subscribe(&member.to_string(), "Animal", &options)
+                .unwrap();
+        }
+        let exchange = Exchange::new(b"cat", "Animal", None, HashMap::new());
+        em.publish(exchange.serialize().unwrap()).unwrap();
+        em.consume_pending();
+
+        // the exchange in flight goes to the member left
+        let key = (LogKey::new("public", "Animal"), 0);
+        let first = em.deliveries[&key].delivered[&workers].service_id.clone();
+        em.unsubscribe(&first, "Animal", &options).unwrap();
+        em.consume_pending();
+        let second = em.deliveries[&key].delivered[&workers].service_id.clone();
+        assert_ne!(first, second);
+
+        // the last member removes the topic, and stays in the group
+        em.subscribe(&second, "Plant", &options).unwrap();
+        em.unsubscribe(&second, "Animal", &options).unwrap();
+        let topics = |em: &ExchangeManager| {
+            em.list_subscriptions(&second)
+                .into_iter()
+                .map(|s| s.topic)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(vec!["PLANT".to_string()], topics(&em));
+
+        // membership spans every topic of the group
+        em.subscribe(&first, "Animal", &options).unwrap();
+        assert!(em.unsubscribe(&first, "Plant", &options).is_err());
+        assert_eq!(vec!["PLANT", "ANIMAL"], topics(&em));
+    }
+
+    #[tokio::test]
+    async fn group_members_need_grants() {
+        let dir = TempDir::new("exchange_manager");
+        let mut em = ExchangeManager::open(&dir).unwrap();
+        let acl: AccessControl = serde_yaml::from_str(
+            r##"
+            rule:
+              - service: "*"
+                subscribe: ["Animal"]
+              - service: auditor
+                subscribe: ["#"]
+            "##,
+        )
+        .unwrap();
+        let acl = Arc::new(Some(acl));
+        let options = SubscribeOptions {
+            group: Some("workers".into()),
+            ..Default::default()
+        };
+        for member in ["worker", "auditor", "intern"] {
+            em.connect(member, drain(), Grants::new(None, acl.clone()))
+                .unwrap();
+        }
+        em.subscribe(&"worker".to_string(), "Animal", &options)
+            .unwrap();
+        em.subscribe(&"auditor".to_string(), "Secret", &options)
+            .unwrap();
+        // joining the group would grant the intern its other topics
+        assert!(em
+            .subscribe(&"intern".to_string(), "Animal", &options)
+            .is_err());
+
+        // the worker joined before, yet never gets exchanges it may not see
+        let exchange = Exchange::new(b"", "Secret", None, HashMap::new());
+        em.publish(exchange.serialize().unwrap()).unwrap();
+        let key = (LogKey::new("public", "Secret"), 0);
+        em.visibility_timeout = Duration::ZERO;
+        let workers = ConsumerId::Group("workers".into());
+        for _ in 0..3 {
+            em.consume_queue();
+            assert_eq!(
+                "auditor",
+                em.deliveries[&key].delivered[&workers].service_id
+            );
+        }
+    }
 
-    use mu_rust_message_common::{exchange::Exchange, TextMessage};
+    #[test]
+    fn reject_invalid_destinations() {
+        let exchange = |topic: &str, tenant: Option<&str>| {
+            Exchange::new(b"", topic, tenant.map(String::from), HashMap::new())
+        };
+        assert!(check_destination(&exchange("Animal", Some("artcoded"))).is_ok());
+        assert!(check_destination(&exchange("a/b", None)).is_ok());
+        assert!(check_destination(&exchange("", None)).is_err());
+        assert!(check_destination(&exchange("..", None)).is_err());
+        assert!(check_destination(&exchange("delta.*", None)).is_err());
+        assert!(check_destination(&exchange("Animal", Some(""))).is_err());
+    }
 
     #[test]
     fn make_text_message() {
@@ -193,8 +1754,3 @@ mod test {
         println!("{s}");
     }
 }
-pub fn to_service_error(e: impl Error) -> ExchangeError {
-    ExchangeError { msg: e.to_string() }
-}
-
-impl Error for ExchangeError {}
@@ -2,44 +2,97 @@ use std::{env::var, error::Error, net::SocketAddr, str::FromStr, sync::Arc, time
 
 use axum::{
     extract::ws::{Message, WebSocket, WebSocketUpgrade},
-    response::IntoResponse,
-    routing::get,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
     Extension, Router,
 };
 use futures_util::StreamExt;
 use mu_rust_common::setup_tracing;
-use mu_rust_message_common::TextMessage;
+use mu_rust_message_common::{exchange::Exchange, TextMessage};
 use tokio::{sync::Mutex, task, time};
 
 use crate::{
-    constants::{PUB_HOST, PUB_INTERVAL_CONSUMER, PUB_INTERVAL_SYNC_FILE, PUB_PORT},
-    exchange_manager::ExchangeManager,
+    acl::{allows_tenant, check_publish, AccessControl, Grants},
+    auth::{authenticating, identify, Authenticator, Identity},
+    constants::{PUB_HOST, PUB_INTERVAL_COMPACTION, PUB_INTERVAL_SYNC_FILE, PUB_PORT},
+    exchange_manager::{ExchangeError, ExchangeManager},
+    publisher::Publisher,
+    subscribers::Subscribers,
+    tls::ClientCertificate,
 };
 
+mod acl;
+mod api;
+mod auth;
 mod constants;
+mod dead_letter;
 mod exchange_manager;
+mod metrics;
+mod publisher;
+mod retention;
+mod scheduler;
+mod segment_log;
+mod subscribers;
+mod subscription_store;
+#[cfg(test)]
+mod test_util;
+mod tls;
+mod topic_index;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     setup_tracing()?;
 
-    let app_state = Arc::new(Mutex::new(ExchangeManager::new()?));
+    let exchange_manager = ExchangeManager::new()?;
+    let metrics = exchange_manager.metrics();
+    let subscribers = exchange_manager.connected();
+    let delivery_trigger = exchange_manager.delivery_trigger();
+    let app_state = Arc::new(Mutex::new(exchange_manager));
     let app = Router::new()
         .route("/", get(ws_handler))
-        .layer(Extension(app_state.clone()));
-    // consume queue periodically
+        .route("/topics/:topic/messages", post(api::publish))
+        .route("/metrics", get(api::metrics))
+        .nest("/admin", api::admin_routes())
+        .layer(Extension(app_state.clone()))
+        .layer(Extension(metrics))
+        .layer(Extension(subscribers))
+        .layer(Extension(Arc::new(Authenticator::from_env())))
+        .layer(Extension(Arc::new(AccessControl::load()?)));
+    // deliver as soon as exchanges are published, and sleep until the next
+    // redelivery or scheduled exchange is due
     let state = app_state.clone();
     let mut queue_consumer = task::spawn(async move {
         tracing::info!("starting to consume queue");
-        let time_between_consume = var(PUB_INTERVAL_CONSUMER)
-            .unwrap_or_else(|_| String::from("10"))
+        loop {
+            let deadline = state.lock().await.next_deadline();
+            let sleep = time::sleep_until(
+                deadline
+                    .map(time::Instant::from_std)
+                    .unwrap_or_else(time::Instant::now),
+            );
+            tokio::select! {
+                _ = delivery_trigger.notified() => state.lock().await.consume_pending(),
+                _ = sleep, if deadline.is_some() => {
+                    let mut em = state.lock().await;
+                    em.publish_due();
+                    em.consume_queue();
+                }
+            }
+        }
+    });
+    let state = app_state.clone();
+    let mut compaction_task = task::spawn(async move {
+        tracing::info!("interval compact topic logs");
+        let time_between_compaction = var(PUB_INTERVAL_COMPACTION)
+            .unwrap_or_else(|_| String::from("60000"))
             .parse::<u64>()
             .unwrap();
 
-        let mut interval = time::interval(Duration::from_millis(time_between_consume));
+        let mut interval = time::interval(Duration::from_millis(time_between_compaction));
         loop {
             interval.tick().await;
-            if let Err(e) = state.lock().await.consume_queue().await {
+            if let Err(e) = state.lock().await.compact() {
                 tracing::error!("{e}");
             }
         }
@@ -59,97 +112,291 @@ async fn main() -> Result<(), Box<dyn Error>> {
             }
         }
     });
+    let tls_config = tls::load_config()?;
     let mut serve = tokio::spawn(async move {
         let host = var(PUB_HOST).unwrap_or_else(|_| String::from("127.0.0.1"));
         let port = var(PUB_PORT).unwrap_or_else(|_| String::from("3000"));
         let addr = SocketAddr::from_str(&format!("{host}:{port}")).unwrap();
-        tracing::info!("listening on {}", addr);
-        axum::Server::bind(&addr)
-            .serve(app.into_make_service())
-            .await
-            .unwrap();
+        if let Some(tls_config) = tls_config {
+            tracing::info!("listening on {} with tls", addr);
+            tls::serve(addr, app, tls_config).await.unwrap();
+        } else {
+            tracing::info!("listening on {}", addr);
+            axum::Server::bind(&addr)
+                .serve(app.into_make_service())
+                .await
+                .unwrap();
+        }
     });
 
     tokio::select! {
         _ = (&mut queue_consumer) => {
             serve.abort();
             sync_file_task.abort();
+            compaction_task.abort();
         },
         _ = (&mut serve) => {
             queue_consumer.abort();
             sync_file_task.abort();
+            compaction_task.abort();
         },
         _ = (&mut sync_file_task) => {
             queue_consumer.abort();
             serve.abort();
+            compaction_task.abort();
+        },
+        _ = (&mut compaction_task) => {
+            queue_consumer.abort();
+            serve.abort();
+            sync_file_task.abort();
         },
     };
     Ok(())
 }
+/// Dead letters are only inspected and replayed by admins, as with the admin API.
+fn admin_required() -> ExchangeError {
+    ExchangeError {
+        msg: "admin access required".into(),
+    }
+}
+
 async fn ws_handler(
     ws: WebSocketUpgrade,
+    headers: HeaderMap,
     Extension(state): Extension<Arc<Mutex<ExchangeManager>>>,
-) -> impl IntoResponse {
-    ws.on_upgrade(|socket| handle_socket(socket, state))
+    Extension(subscribers): Extension<Subscribers>,
+    Extension(authenticator): Extension<Arc<Option<Authenticator>>>,
+    Extension(acl): Extension<Arc<Option<AccessControl>>>,
+    client_certificate: Option<Extension<ClientCertificate>>,
+) -> Response {
+    let identity = match identify(
+        authenticator.as_ref().as_ref(),
+        &headers,
+        client_certificate.map(|Extension(c)| c),
+    ) {
+        Ok(identity) => identity,
+        Err(e) => {
+            tracing::warn!("rejected connection: {e}");
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+    };
+    let admin = identity.is_admin(authenticating(authenticator.as_ref().as_ref()));
+    ws.on_upgrade(move |socket| handle_socket(socket, state, subscribers, identity, admin, acl))
 }
 
-async fn handle_socket(socket: WebSocket, state: Arc<Mutex<ExchangeManager>>) {
+async fn handle_socket(
+    socket: WebSocket,
+    state: Arc<Mutex<ExchangeManager>>,
+    subscribers: Subscribers,
+    identity: Identity,
+    admin: bool,
+    acl: Arc<Option<AccessControl>>,
+) {
+    let Identity {
+        service_id: identity,
+        claims,
+    } = identity;
     let (sender, mut receiver) = socket.split();
     let task_result = tokio::spawn(async move {
-        let service_id: String = {
-            let mut service_id = String::new();
+        let connected = {
+            let mut connected = None;
             while let Some(Ok(message)) = receiver.next().await {
                 if let Message::Text(message) = message {
-                    if let Ok(TextMessage::Connect(sid)) =
+                    if let Ok(TextMessage::Connect(mut sid)) =
                         serde_json::from_str::<TextMessage>(&message)
                     {
+                        // the token or certificate decides who the service is
+                        if let Some(identity) = &identity {
+                            if identity != &sid {
+                                tracing::warn!("{sid} connects with the credentials of {identity}");
+                                sid = identity.clone();
+                            }
+                        }
                         tracing::info!("receive connect message from {sid}");
                         let mut em = state.lock().await;
-                        em.connect(&sid, sender);
-                        service_id = sid;
+                        let grants = Grants::new(claims.clone(), acl.clone());
+                        match em.connect(&sid, sender, grants) {
+                            Ok(connection_id) => connected = Some((sid, connection_id)),
+                            Err(e) => tracing::warn!("rejected connection of {sid}: {e}"),
+                        }
                         break;
                     }
                 }
             }
-            service_id
+            connected
         };
+        let Some((service_id, connection_id)) = connected else {
+            return;
+        };
+        let publish_buffer_size = state.lock().await.publish_buffer_size();
+        let publisher = Publisher::spawn(
+            state.clone(),
+            subscribers.clone(),
+            &service_id,
+            connection_id,
+            publish_buffer_size,
+        );
         while let Some(Ok(message)) = receiver.next().await {
             match message {
-                Message::Text(message) => {
-                    if let Ok(TextMessage::Subscribe(topic)) =
-                        serde_json::from_str::<TextMessage>(&message)
-                    {
+                Message::Text(message) => match serde_json::from_str::<TextMessage>(&message) {
+                    Ok(TextMessage::Subscribe(topic)) => {
                         tracing::info!("receive subscribe message from {service_id}");
                         let mut em = state.lock().await;
-                        em.subscribe(&service_id, &topic);
+                        match em.subscribe(&service_id, &topic, &Default::default()) {
+                            Ok(subscription) => subscribers.send_text(
+                                &service_id,
+                                connection_id,
+                                &TextMessage::Subscribed(subscription),
+                            ),
+                            Err(e) => {
+                                tracing::error!("could not subscribe {service_id} to {topic}: {e}");
+                                subscribers.send_error(&service_id, connection_id, &e);
+                            }
+                        }
                     }
-                }
+                    Ok(TextMessage::SubscribeWith { topic, options }) => {
+                        tracing::info!("receive subscribe message from {service_id} {options:?}");
+                        let mut em = state.lock().await;
+                        match em.subscribe(&service_id, &topic, &options) {
+                            Ok(subscription) => subscribers.send_text(
+                                &service_id,
+                                connection_id,
+                                &TextMessage::Subscribed(subscription),
+                            ),
+                            Err(e) => {
+                                tracing::error!("could not subscribe {service_id} to {topic}: {e}");
+                                subscribers.send_error(&service_id, connection_id, &e);
+                            }
+                        }
+                    }
+                    Ok(TextMessage::Unsubscribe { topic, options }) => {
+                        tracing::info!("receive unsubscribe message from {service_id}");
+                        let mut em = state.lock().await;
+                        match em.unsubscribe(&service_id, &topic, &options) {
+                            Ok(subscription) => subscribers.send_text(
+                                &service_id,
+                                connection_id,
+                                &TextMessage::Unsubscribed(subscription),
+                            ),
+                            Err(e) => {
+                                tracing::warn!(
+                                    "could not unsubscribe {service_id} from {topic}: {e}"
+                                );
+                                subscribers.send_error(&service_id, connection_id, &e);
+                            }
+                        }
+                    }
+                    Ok(TextMessage::ListSubscriptions) => {
+                        let subscriptions = state.lock().await.list_subscriptions(&service_id);
+                        subscribers.send_text(
+                            &service_id,
+                            connection_id,
+                            &TextMessage::Subscriptions(subscriptions),
+                        );
+                    }
+                    Ok(TextMessage::Ack(message_id)) => {
+                        tracing::debug!("receive ack {message_id} from {service_id}");
+                        let mut em = state.lock().await;
+                        em.ack(&service_id, &message_id);
+                    }
+                    Ok(TextMessage::Nack(message_id)) => {
+                        tracing::debug!("receive nack {message_id} from {service_id}");
+                        let mut em = state.lock().await;
+                        em.nack(&service_id, &message_id);
+                    }
+                    Ok(TextMessage::InspectDeadLetters) if !admin => {
+                        tracing::warn!("{service_id} is not allowed to inspect dead letters");
+                        subscribers.send_error(&service_id, connection_id, &admin_required());
+                    }
+                    Ok(TextMessage::InspectDeadLetters) => {
+                        let dead_letters = state.lock().await.inspect_dead_letters(|tenant| {
+                            allows_tenant(
+                                claims.as_ref(),
+                                acl.as_ref().as_ref(),
+                                &service_id,
+                                tenant,
+                            )
+                        });
+                        let sent = match (
+                            dead_letters,
+                            subscribers.connection(&service_id, connection_id),
+                        ) {
+                            (Ok(dead_letters), Some(outbound)) => {
+                                let mut sent = Ok(());
+                                for binary in dead_letters {
+                                    sent = outbound.send(Message::Binary(binary)).await;
+                                    if sent.is_err() {
+                                        break;
+                                    }
+                                }
+                                sent
+                            }
+                            (Ok(_), None) => Ok(()),
+                            (Err(e), _) => Err(e),
+                        };
+                        if let Err(e) = sent {
+                            tracing::error!("could not send dead letters to {service_id}: {e}");
+                        }
+                    }
+                    Ok(TextMessage::ReplayDeadLetters) if !admin => {
+                        tracing::warn!("{service_id} is not allowed to replay dead letters");
+                        subscribers.send_error(&service_id, connection_id, &admin_required());
+                    }
+                    Ok(TextMessage::ReplayDeadLetters) => {
+                        let mut em = state.lock().await;
+                        match em.replay_dead_letters(|tenant| {
+                            allows_tenant(
+                                claims.as_ref(),
+                                acl.as_ref().as_ref(),
+                                &service_id,
+                                tenant,
+                            )
+                        }) {
+                            Ok(count) => {
+                                tracing::info!("{service_id} replayed {count} dead letters")
+                            }
+                            Err(e) => tracing::error!("could not replay dead letters: {e}"),
+                        }
+                    }
+                    Ok(TextMessage::ReplyQueue(topic)) => {
+                        let mut em = state.lock().await;
+                        if let Err(e) = em.open_reply_queue(&service_id, &topic) {
+                            tracing::error!("could not open reply queue for {service_id}: {e}");
+                        }
+                    }
+                    _ => {
+                        tracing::debug!("ignoring text message from {service_id}: {message}");
+                    }
+                },
                 Message::Binary(exchange_binary) => {
-                    let mut em = state.lock().await;
                     tracing::debug!("receive binary message from {service_id}");
-                    if let Err(e) = em.publish(exchange_binary).await {
-                        tracing::error!("error in exchange {e:?}");
-                    }
-                }
-                Message::Ping(_) => {
-                    let mut em = state.lock().await;
-                    if let Err(e) = em.pong(&service_id).await {
-                        tracing::error!("could not send pong message {e:?}");
+                    let allowed = match Exchange::deserialize(&exchange_binary) {
+                        Ok(exchange) => check_publish(
+                            claims.as_ref(),
+                            acl.as_ref().as_ref(),
+                            &service_id,
+                            &exchange,
+                        ),
+                        // invalid exchanges are rejected when published
+                        Err(_) => Ok(()),
+                    };
+                    if let Err(e) = allowed {
+                        tracing::warn!("rejected exchange from {service_id}: {e}");
+                        subscribers.send_error(&service_id, connection_id, &e);
+                    } else if let Err(e) = publisher.try_publish(exchange_binary) {
+                        tracing::warn!("rejected exchange from {service_id}: {e}");
+                        subscribers.send_error(&service_id, connection_id, &e);
                     }
                 }
+                Message::Ping(_) => subscribers.pong(&service_id, connection_id),
                 Message::Pong(text) => {
                     tracing::debug!("received pong message {text:?}");
                 }
-                Message::Close(_) => {
-                    let mut em = state.lock().await;
-                    if let Err(e) = em.close_connection(&service_id).await {
-                        tracing::error!("could not unsubscribe {e:?}");
-                    } else {
-                        tracing::info!("{service_id} unsubscribed");
-                    }
-                }
+                Message::Close(_) => break,
             }
         }
+        state.lock().await.disconnect(&service_id, connection_id);
+        tracing::info!("connection {connection_id} of {service_id} closed");
     })
     .await;
 
@@ -0,0 +1,125 @@
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+
+use crate::exchange_manager::{to_service_error, ExchangeError};
+
+/// Why an exchange was not delivered, as the `reason` label of
+/// `broker_exchanges_failed_total`.
+pub const FAILED_SEND: &str = "send";
+pub const FAILED_POISON: &str = "poison";
+pub const FAILED_DEAD_LETTER: &str = "dead-letter";
+pub const FAILED_EXPIRED: &str = "expired";
+pub const FAILED_DROPPED: &str = "dropped";
+
+/// Prometheus metrics of the broker, exposed on `/metrics`.
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    registry: Registry,
+    pub published: IntCounterVec,
+    pub delivered: IntCounterVec,
+    pub failed: IntCounterVec,
+    pub subscribers: IntGauge,
+    pub queue_size: IntGaugeVec,
+    pub delivery_latency: HistogramVec,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Metrics, ExchangeError> {
+        let registry =
+            Registry::new_custom(Some("broker".into()), None).map_err(to_service_error)?;
+        let published = IntCounterVec::new(
+            Opts::new("exchanges_published_total", "Exchanges published"),
+            &["tenant", "topic"],
+        )
+        .map_err(to_service_error)?;
+        let delivered = IntCounterVec::new(
+            Opts::new(
+                "exchanges_delivered_total",
+                "Exchanges sent to a subscriber",
+            ),
+            &["tenant", "topic"],
+        )
+        .map_err(to_service_error)?;
+        let failed = IntCounterVec::new(
+            Opts::new(
+                "exchanges_failed_total",
+                "Exchanges that could not be delivered",
+            ),
+            &["tenant", "topic", "reason"],
+        )
+        .map_err(to_service_error)?;
+        let subscribers =
+            IntGauge::new("subscribers", "Connected subscribers").map_err(to_service_error)?;
+        let queue_size = IntGaugeVec::new(
+            Opts::new("queue_size", "Exchanges retained in the topic log"),
+            &["tenant", "topic"],
+        )
+        .map_err(to_service_error)?;
+        let delivery_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "delivery_latency_seconds",
+                "Time from the exchange timestamp to its delivery",
+            )
+            .buckets(vec![
+                0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 30.0, 60.0, 300.0,
+            ]),
+            &["tenant", "topic"],
+        )
+        .map_err(to_service_error)?;
+        registry
+            .register(Box::new(published.clone()))
+            .and_then(|_| registry.register(Box::new(delivered.clone())))
+            .and_then(|_| registry.register(Box::new(failed.clone())))
+            .and_then(|_| registry.register(Box::new(subscribers.clone())))
+            .and_then(|_| registry.register(Box::new(queue_size.clone())))
+            .and_then(|_| registry.register(Box::new(delivery_latency.clone())))
+            .map_err(to_service_error)?;
+        Ok(Metrics {
+            registry,
+            published,
+            delivered,
+            failed,
+            subscribers,
+            queue_size,
+            delivery_latency,
+        })
+    }
+
+    pub fn fail(&self, tenant: &str, topic: &str, reason: &str) {
+        self.failed
+            .with_label_values(&[tenant, topic, reason])
+            .inc();
+    }
+
+    /// The metrics in the Prometheus text format.
+    pub fn encode(&self) -> Result<String, ExchangeError> {
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .map_err(to_service_error)?;
+        String::from_utf8(buffer).map_err(to_service_error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Metrics, FAILED_POISON};
+
+    #[test]
+    fn encode_metrics() {
+        let metrics = Metrics::new().unwrap();
+        metrics
+            .published
+            .with_label_values(&["public", "ANIMAL"])
+            .inc();
+        metrics.fail("public", "ANIMAL", FAILED_POISON);
+        metrics.subscribers.set(2);
+        let encoded = metrics.encode().unwrap();
+        assert!(encoded
+            .contains(r#"broker_exchanges_published_total{tenant="public",topic="ANIMAL"} 1"#));
+        assert!(encoded.contains(r#"reason="poison""#));
+        assert!(encoded.contains("broker_subscribers 2"));
+    }
+}
@@ -0,0 +1,149 @@
+use std::sync::Arc;
+
+use tokio::sync::{
+    mpsc::{self, error::TrySendError},
+    Mutex,
+};
+
+use crate::{
+    exchange_manager::{wait_for_consumers, ExchangeError, ExchangeManager},
+    subscribers::{ConnectionId, Subscribers},
+};
+
+/// Exchanges published on a connection, appended in order by a task of their
+/// own. A publisher held back by a blocking consumer keeps reading its
+/// socket, so that its own acknowledgements still get through.
+#[derive(Debug, Clone)]
+pub struct Publisher {
+    service_id: String,
+    sender: mpsc::Sender<Vec<u8>>,
+}
+
+impl Publisher {
+    pub fn spawn(
+        state: Arc<Mutex<ExchangeManager>>,
+        subscribers: Subscribers,
+        service_id: &str,
+        connection_id: ConnectionId,
+        capacity: usize,
+    ) -> Publisher {
+        let (sender, mut receiver) = mpsc::channel::<Vec<u8>>(capacity);
+        let publisher = Publisher {
+            service_id: service_id.to_owned(),
+            sender,
+        };
+        let service_id = service_id.to_owned();
+        tokio::spawn(async move {
+            while let Some(exchange_binary) = receiver.recv().await {
+                let published = state.lock().await.publish(exchange_binary);
+                match published {
+                    Ok(Some(key)) => wait_for_consumers(&state, &key).await,
+                    Ok(None) => {}
+                    Err(e) => {
+                        tracing::error!("error in exchange from {service_id}: {e}");
+                        subscribers.send_error(&service_id, connection_id, &e);
+                    }
+                }
+            }
+        });
+        publisher
+    }
+
+    /// Queue the exchange without waiting. It is rejected when the queue is
+    /// full, the publisher having to retry later.
+    pub fn try_publish(&self, exchange_binary: Vec<u8>) -> Result<(), ExchangeError> {
+        self.sender
+            .try_send(exchange_binary)
+            .map_err(|e| ExchangeError {
+                msg: match e {
+                    TrySendError::Full(_) => {
+                        format!(
+                            "too many exchanges pending for {}, retry later",
+                            self.service_id
+                        )
+                    }
+                    TrySendError::Closed(_) => format!("{} is disconnected", self.service_id),
+                },
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, sync::Arc, time::Duration};
+
+    use axum::extract::ws::Message;
+    use futures_util::sink::unfold;
+    use mu_rust_message_common::{
+        exchange::{Exchange, HEADER_DELIVER_AT},
+        Backpressure, SlowConsumerPolicy, SubscribeOptions,
+    };
+    use tokio::{
+        sync::{mpsc, Mutex},
+        time::{timeout, Instant},
+    };
+
+    use super::Publisher;
+    use crate::{exchange_manager::ExchangeManager, test_util::TempDir};
+
+    #[tokio::test]
+    async fn publish_and_consume_on_one_connection() {
+        let dir = TempDir::new("publisher");
+        let mut em = ExchangeManager::open(&dir).unwrap();
+        let (frames, mut received) = mpsc::unbounded_channel();
+        let sink = Box::pin(unfold(frames, |frames, message: Message| async move {
+            frames.send(message).map_err(|_| "closed")?;
+            Ok::<_, &str>(frames)
+        }));
+        let service_id = "Nordine".to_string();
+        let connection_id = em.connect(&service_id, sink, Default::default()).unwrap();
+        let options = SubscribeOptions {
+            backpressure: Some(Backpressure {
+                buffer: 1,
+                policy: SlowConsumerPolicy::Block,
+            }),
+            ..Default::default()
+        };
+        em.subscribe(&service_id, "Animal", &options).unwrap();
+        let subscribers = em.connected();
+        let state = Arc::new(Mutex::new(em));
+        let publisher =
+            Publisher::spawn(state.clone(), subscribers, &service_id, connection_id, 10);
+
+        // the consumer blocks the log after the first exchange, yet publishing
+        // does not wait and the connection keeps acknowledging
+        for i in 0..3 {
+            let exchange = Exchange::new(&[i], "Animal", None, HashMap::new());
+            publisher
+                .try_publish(exchange.serialize().unwrap())
+                .unwrap();
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut acked = vec![];
+        while acked.len() < 3 {
+            assert!(Instant::now() < deadline, "only {acked:?} delivered");
+            state.lock().await.consume_pending();
+            if let Ok(Some(Message::Binary(binary))) =
+                timeout(Duration::from_millis(50), received.recv()).await
+            {
+                let exchange = Exchange::deserialize(&binary).unwrap();
+                state.lock().await.ack(&service_id, exchange.delivery_id());
+                acked.push(exchange.message[0]);
+            }
+        }
+        assert_eq!(vec![0, 1, 2], acked);
+
+        let mut exchange = Exchange::new(b"", "Animal", None, HashMap::new());
+        exchange
+            .headers
+            .insert(HEADER_DELIVER_AT.into(), "tomorrow".into());
+        publisher
+            .try_publish(exchange.serialize().unwrap())
+            .unwrap();
+        let frame = timeout(Duration::from_secs(1), received.recv()).await;
+        assert!(
+            matches!(frame, Ok(Some(Message::Text(text))) if text.contains("Error")),
+            "no error frame"
+        );
+    }
+}
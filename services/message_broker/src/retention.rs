@@ -0,0 +1,189 @@
+use std::env::var;
+
+use chrono::NaiveDateTime;
+use mu_rust_message_common::{exchange::Exchange, topic::TopicPattern};
+use serde::Deserialize;
+
+use crate::{
+    constants::PUB_RETENTION_CONFIG,
+    exchange_manager::{to_service_error, ExchangeError},
+    segment_log::TopicLog,
+};
+
+/// What happens to an exchange once it is past its retention.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExpiredAction {
+    #[default]
+    Drop,
+    DeadLetter,
+}
+
+/// Retention of the topics matching a pattern. Limits apply to each topic
+/// on its own; the oldest exchanges go first.
+#[derive(Debug, Deserialize)]
+pub struct RetentionPolicy {
+    pub topic: TopicPattern,
+    /// Maximum age in milliseconds, based on the exchange timestamp.
+    #[serde(default)]
+    pub max_age: Option<i64>,
+    #[serde(default)]
+    pub max_count: Option<usize>,
+    #[serde(default)]
+    pub max_bytes: Option<usize>,
+    #[serde(default)]
+    pub on_expired: ExpiredAction,
+}
+
+/// Retention policies loaded from the TOML file set in `PUB_RETENTION_CONFIG`:
+///
+/// ```toml
+/// [[policy]]
+/// topic = "delta.#"
+/// max_age = 86400000
+/// max_count = 10000
+/// on_expired = "dead-letter"
+/// ```
+///
+/// The first matching policy applies. Exchanges with an expired ttl header
+/// are dropped when they are about to be delivered, even without policy, or
+/// dead-lettered when the policy of their topic says so.
+#[derive(Debug, Default, Deserialize)]
+pub struct RetentionPolicies {
+    #[serde(default, rename = "policy")]
+    policies: Vec<RetentionPolicy>,
+}
+
+impl RetentionPolicies {
+    pub fn load() -> Result<RetentionPolicies, ExchangeError> {
+        match var(PUB_RETENTION_CONFIG) {
+            Ok(path) => {
+                let content = std::fs::read_to_string(&path).map_err(to_service_error)?;
+                RetentionPolicies::parse(&content)
+            }
+            Err(_) => Ok(RetentionPolicies::default()),
+        }
+    }
+
+    pub fn parse(content: &str) -> Result<RetentionPolicies, ExchangeError> {
+        toml::from_str(content).map_err(to_service_error)
+    }
+
+    pub fn policy_for(&self, topic: &str) -> Option<&RetentionPolicy> {
+        self.policies.iter().find(|p| p.topic.matches(topic))
+    }
+
+    /// First offset of the topic log to retain, and what to do with the
+    /// records before it.
+    pub fn retained_from(
+        &self,
+        topic: &str,
+        log: &TopicLog,
+        now: NaiveDateTime,
+    ) -> Result<(u64, ExpiredAction), ExchangeError> {
+        let mut from = log.start_offset();
+        let Some(policy) = self.policy_for(topic) else {
+            return Ok((from, ExpiredAction::Drop));
+        };
+        let end = log.next_offset();
+        if let Some(max_count) = policy.max_count {
+            from = from.max(end.saturating_sub(max_count as u64));
+        }
+        if let Some(max_bytes) = policy.max_bytes {
+            let mut size = log.size();
+            let mut offset = log.start_offset();
+            while size > max_bytes as u64 && offset < end {
+                size -= log.record_size(offset);
+                offset += 1;
+            }
+            from = from.max(offset);
+        }
+        if let Some(max_age) = policy.max_age {
+            while from < end {
+                let expired = match log.read(from)? {
+                    Some(record) => Exchange::deserialize(&record)
+                        .map(|e| (now - e.timestamp).num_milliseconds() > max_age)
+                        .unwrap_or(true),
+                    None => true,
+                };
+                if !expired {
+                    break;
+                }
+                from += 1;
+            }
+        }
+        Ok((from, policy.on_expired))
+    }
+}
+
+pub fn is_ttl_expired(exchange: &Exchange, now: NaiveDateTime) -> bool {
+    exchange.expires_at().map(|e| e <= now).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, time::Duration};
+
+    use chrono::Local;
+    use mu_rust_message_common::exchange::Exchange;
+
+    use super::{is_ttl_expired, ExpiredAction, RetentionPolicies};
+    use crate::{segment_log::TopicLog, test_util::TempDir};
+
+    #[test]
+    fn retain_topic_log() {
+        let policies = RetentionPolicies::parse(
+            r#"
+            [[policy]]
+            topic = "delta.*"
+            max_count = 2
+            on_expired = "dead-letter"
+
+            [[policy]]
+            topic = "animal"
+            max_age = 1000
+            "#,
+        )
+        .unwrap();
+        let now = Local::now().naive_local();
+        let dir = TempDir::new("retention");
+
+        let mut animals = TopicLog::open(&dir.join("animal"), 1024).unwrap();
+        let old_animal = Exchange {
+            timestamp: now - chrono::Duration::seconds(2),
+            ..Exchange::new(b"", "Animal", None, HashMap::new())
+        };
+        let animal = Exchange::new(b"", "Animal", None, HashMap::new());
+        for exchange in [&old_animal, &old_animal, &animal] {
+            animals.append(&exchange.serialize().unwrap()).unwrap();
+        }
+        assert_eq!(
+            (2, ExpiredAction::Drop),
+            policies.retained_from("ANIMAL", &animals, now).unwrap()
+        );
+
+        let mut deltas = TopicLog::open(&dir.join("delta"), 1024).unwrap();
+        for _ in 0..5 {
+            let delta = Exchange::new(b"", "delta.created", None, HashMap::new());
+            deltas.append(&delta.serialize().unwrap()).unwrap();
+        }
+        assert_eq!(
+            (3, ExpiredAction::DeadLetter),
+            policies
+                .retained_from("DELTA.CREATED", &deltas, now)
+                .unwrap()
+        );
+        assert_eq!(
+            (0, ExpiredAction::Drop),
+            policies.retained_from("PERSON", &deltas, now).unwrap()
+        );
+
+        let expired_ttl = Exchange {
+            timestamp: now - chrono::Duration::seconds(2),
+            ..Exchange::new(b"", "person", None, HashMap::new())
+        }
+        .with_ttl(Duration::from_millis(500));
+        assert!(is_ttl_expired(&expired_ttl, now));
+        assert!(!is_ttl_expired(&animal, now));
+    }
+}
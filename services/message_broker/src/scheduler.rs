@@ -0,0 +1,225 @@
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use chrono::{Local, NaiveDateTime, TimeZone};
+use cron::Schedule;
+use mu_rust_message_common::exchange::Exchange;
+use serde::{Deserialize, Serialize};
+
+use crate::exchange_manager::{to_service_error, ExchangeError};
+
+/// An exchange held back until due. A recurring one is published again,
+/// with a fresh id, at every occurrence of its cron expression.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Scheduled {
+    pub id: String,
+    pub deliver_at: NaiveDateTime,
+    #[serde(default)]
+    pub cron: Option<String>,
+    pub exchange: Exchange,
+}
+
+/// A scheduled exchange, as listed by the admin API.
+#[derive(Debug, Serialize)]
+pub struct ScheduledView {
+    pub id: String,
+    pub deliver_at: NaiveDateTime,
+    pub cron: Option<String>,
+    pub topic: String,
+    pub tenant: Option<String>,
+}
+
+/// Next occurrence of the cron expression after that time.
+fn next_occurrence(cron: &str, after: NaiveDateTime) -> Result<NaiveDateTime, ExchangeError> {
+    let schedule = Schedule::from_str(cron).map_err(to_service_error)?;
+    let after = Local
+        .from_local_datetime(&after)
+        .earliest()
+        .unwrap_or_else(Local::now);
+    schedule
+        .after(&after)
+        .next()
+        .map(|next| next.naive_local())
+        .ok_or_else(|| ExchangeError {
+            msg: format!("{cron} never occurs"),
+        })
+}
+
+/// Exchanges waiting for their delivery time, kept across broker restarts.
+#[derive(Debug)]
+pub struct Scheduler {
+    path: PathBuf,
+    scheduled: Vec<Scheduled>,
+    dirty: bool,
+}
+
+impl Scheduler {
+    pub fn open(path: &Path) -> Result<Scheduler, ExchangeError> {
+        let scheduled = if path.exists() {
+            let content = std::fs::read(path).map_err(to_service_error)?;
+            serde_json::from_slice(&content).map_err(to_service_error)?
+        } else {
+            vec![]
+        };
+        Ok(Scheduler {
+            path: path.to_path_buf(),
+            scheduled,
+            dirty: false,
+        })
+    }
+
+    /// Hold the exchange back until that time, under the id of the exchange.
+    /// It is saved right away, the publisher taking it as published.
+    pub fn hold(
+        &mut self,
+        exchange: Exchange,
+        deliver_at: NaiveDateTime,
+    ) -> Result<String, ExchangeError> {
+        let id = exchange.id().to_owned();
+        self.scheduled.push(Scheduled {
+            id: id.clone(),
+            deliver_at,
+            cron: None,
+            exchange,
+        });
+        self.dirty = true;
+        self.save()?;
+        Ok(id)
+    }
+
+    /// Publish a copy of the exchange at every occurrence of the cron
+    /// expression, with seconds: `0 */15 * * * *` every quarter of an hour.
+    pub fn repeat(&mut self, exchange: Exchange, cron: &str) -> Result<String, ExchangeError> {
+        let deliver_at = next_occurrence(cron, Local::now().naive_local())?;
+        let id = uuid::Uuid::new_v4().to_string();
+        self.scheduled.push(Scheduled {
+            id: id.clone(),
+            deliver_at,
+            cron: Some(cron.to_owned()),
+            exchange,
+        });
+        self.dirty = true;
+        Ok(id)
+    }
+
+    pub fn cancel(&mut self, id: &str) -> bool {
+        let count = self.scheduled.len();
+        self.scheduled.retain(|s| s.id != id);
+        self.dirty |= count != self.scheduled.len();
+        count != self.scheduled.len()
+    }
+
+    /// When the next scheduled exchange is due.
+    pub fn next_due(&self) -> Option<NaiveDateTime> {
+        self.scheduled.iter().map(|s| s.deliver_at).min()
+    }
+
+    pub fn list(&self) -> Vec<ScheduledView> {
+        self.scheduled
+            .iter()
+            .map(|s| ScheduledView {
+                id: s.id.clone(),
+                deliver_at: s.deliver_at,
+                cron: s.cron.clone(),
+                topic: s.exchange.topic.clone(),
+                tenant: s.exchange.tenant.clone(),
+            })
+            .collect()
+    }
+
+    /// Remove and return the exchanges due at that time, stamped with the
+    /// current time. Recurring ones are returned once, even if several
+    /// occurrences were missed, and stay scheduled for their next occurrence.
+    pub fn take_due(&mut self, now: NaiveDateTime) -> Vec<Exchange> {
+        let mut due = vec![];
+        let mut kept = vec![];
+        for mut scheduled in std::mem::take(&mut self.scheduled) {
+            if scheduled.deliver_at > now {
+                kept.push(scheduled);
+                continue;
+            }
+            self.dirty = true;
+            let Some(cron) = &scheduled.cron else {
+                due.push(Exchange {
+                    timestamp: Local::now().naive_local(),
+                    ..scheduled.exchange
+                });
+                continue;
+            };
+            due.push(
+                Exchange {
+                    timestamp: Local::now().naive_local(),
+                    ..scheduled.exchange.clone()
+                }
+                .with_new_id(),
+            );
+            match next_occurrence(cron, now) {
+                Ok(next) => {
+                    scheduled.deliver_at = next;
+                    kept.push(scheduled);
+                }
+                Err(e) => tracing::warn!("stop schedule {}: {e}", scheduled.id),
+            }
+        }
+        self.scheduled = kept;
+        due
+    }
+
+    pub fn save(&mut self) -> Result<(), ExchangeError> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let content = serde_json::to_vec(&self.scheduled).map_err(to_service_error)?;
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, content).map_err(to_service_error)?;
+        std::fs::rename(&tmp, &self.path).map_err(to_service_error)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use chrono::{Duration, Local};
+    use mu_rust_message_common::exchange::Exchange;
+
+    use super::Scheduler;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn hold_until_due() {
+        let dir = TempDir::new("scheduler");
+        let path = dir.join("scheduled.json");
+
+        let now = Local::now().naive_local();
+        let mut scheduler = Scheduler::open(&path).unwrap();
+        let exchange = Exchange::new(b"tick", "clock", None, HashMap::new());
+        let held = scheduler
+            .hold(exchange.clone(), now + Duration::minutes(15))
+            .unwrap();
+        // saved without waiting for the next sync
+        assert_eq!(1, Scheduler::open(&path).unwrap().list().len());
+        let published_at = exchange.timestamp;
+        let repeated = scheduler.repeat(exchange, "0 * * * * *").unwrap();
+        assert!(scheduler.repeat(Exchange::default(), "not cron").is_err());
+        scheduler.save().unwrap();
+
+        let mut scheduler = Scheduler::open(&path).unwrap();
+        assert_eq!(2, scheduler.list().len());
+        assert!(scheduler.take_due(now).is_empty());
+        let due = scheduler.take_due(now + Duration::minutes(16));
+        assert_eq!(2, due.len());
+        assert!(due.iter().any(|e| e.id() == held));
+        assert!(due.iter().all(|e| e.timestamp > published_at));
+        let schedules = scheduler.list();
+        assert_eq!(1, schedules.len());
+        assert_eq!(repeated, schedules[0].id);
+        assert!(schedules[0].deliver_at > now + Duration::minutes(16));
+        assert!(scheduler.cancel(&repeated));
+        assert!(scheduler.list().is_empty());
+    }
+}
@@ -0,0 +1,338 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::exchange_manager::{to_service_error, ExchangeError};
+
+const SEGMENT_EXTENSION: &str = "log";
+const START_OFFSET_FILE: &str = "start.offset";
+const LENGTH_PREFIX: u64 = 4;
+
+/// Escape a tenant or topic so that it can be used as a file name.
+/// Bytes other than ASCII alphanumerics and `-` are escaped as `_xx`, dots
+/// included, so that no name can resolve to `.` or `..`.
+pub fn encode_name(name: &str) -> String {
+    let mut encoded = String::new();
+    for b in name.bytes() {
+        if b.is_ascii_alphanumeric() || b == b'-' {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("_{b:02x}"));
+        }
+    }
+    encoded
+}
+
+pub fn decode_name(encoded: &str) -> Option<String> {
+    let mut bytes = vec![];
+    let mut chars = encoded.bytes();
+    while let Some(b) = chars.next() {
+        if b == b'_' {
+            let hex = [chars.next()?, chars.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Identifies the log of a topic within a tenant. Topics are case-insensitive.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LogKey {
+    pub tenant: String,
+    pub topic: String,
+}
+
+impl LogKey {
+    pub fn new(tenant: &str, topic: &str) -> LogKey {
+        LogKey {
+            tenant: tenant.to_string(),
+            topic: topic.to_uppercase(),
+        }
+    }
+
+    /// Relative directory of the log, also used as a stable string key.
+    pub fn path(&self) -> String {
+        format!("{}/{}", encode_name(&self.tenant), encode_name(&self.topic))
+    }
+}
+
+#[derive(Debug)]
+struct Segment {
+    base_offset: u64,
+    path: PathBuf,
+    file: File,
+    /// Byte position of each record in the file.
+    positions: Vec<u64>,
+    size: u64,
+}
+
+impl Segment {
+    fn path(dir: &Path, base_offset: u64) -> PathBuf {
+        dir.join(format!("{base_offset:020}.{SEGMENT_EXTENSION}"))
+    }
+
+    fn open(dir: &Path, base_offset: u64) -> Result<Segment, ExchangeError> {
+        let path = Segment::path(dir, base_offset);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)
+            .map_err(to_service_error)?;
+        let len = file.metadata().map_err(to_service_error)?.len();
+        let mut positions = vec![];
+        let mut position = 0;
+        let mut length = [0u8; LENGTH_PREFIX as usize];
+        while position + LENGTH_PREFIX <= len {
+            file.seek(SeekFrom::Start(position))
+                .map_err(to_service_error)?;
+            file.read_exact(&mut length).map_err(to_service_error)?;
+            let next = position + LENGTH_PREFIX + u32::from_le_bytes(length) as u64;
+            if next > len {
+                break;
+            }
+            positions.push(position);
+            position = next;
+        }
+        if position < len {
+            tracing::warn!("truncate incomplete record at {position} in {path:?}");
+            file.set_len(position).map_err(to_service_error)?;
+        }
+        Ok(Segment {
+            base_offset,
+            path,
+            file,
+            positions,
+            size: position,
+        })
+    }
+
+    fn end_offset(&self) -> u64 {
+        self.base_offset + self.positions.len() as u64
+    }
+
+    fn append(&mut self, record: &[u8]) -> Result<(), ExchangeError> {
+        let length = u32::try_from(record.len()).map_err(to_service_error)?;
+        let mut buffer = Vec::with_capacity(record.len() + LENGTH_PREFIX as usize);
+        buffer.extend_from_slice(&length.to_le_bytes());
+        buffer.extend_from_slice(record);
+        self.file.write_all(&buffer).map_err(to_service_error)?;
+        self.positions.push(self.size);
+        self.size += buffer.len() as u64;
+        Ok(())
+    }
+
+    fn record_size(&self, index: usize) -> u64 {
+        let next = self.positions.get(index + 1).copied().unwrap_or(self.size);
+        next - self.positions[index] - LENGTH_PREFIX
+    }
+
+    fn read(&self, index: usize) -> Result<Vec<u8>, ExchangeError> {
+        let mut record = vec![0u8; self.record_size(index) as usize];
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(self.positions[index] + LENGTH_PREFIX))
+            .map_err(to_service_error)?;
+        file.read_exact(&mut record).map_err(to_service_error)?;
+        Ok(record)
+    }
+}
+
+/// Append-only log of a topic, split in segment files named after the offset
+/// of their first record. Records before `start_offset` are no longer
+/// retained; whole segments below it are deleted.
+#[derive(Debug)]
+pub struct TopicLog {
+    dir: PathBuf,
+    segments: Vec<Segment>,
+    start_offset: u64,
+    segment_size: u64,
+}
+
+impl TopicLog {
+    pub fn open(dir: &Path, segment_size: u64) -> Result<TopicLog, ExchangeError> {
+        std::fs::create_dir_all(dir).map_err(to_service_error)?;
+        let mut base_offsets = vec![];
+        for entry in std::fs::read_dir(dir).map_err(to_service_error)? {
+            let path = entry.map_err(to_service_error)?.path();
+            if path.extension().and_then(|e| e.to_str()) == Some(SEGMENT_EXTENSION) {
+                if let Some(base_offset) = path
+                    .file_stem()
+                    .and_then(|s| s.to_str())
+                    .and_then(|s| s.parse::<u64>().ok())
+                {
+                    base_offsets.push(base_offset);
+                }
+            }
+        }
+        base_offsets.sort();
+        let persisted_start = match std::fs::read_to_string(dir.join(START_OFFSET_FILE)) {
+            Ok(start) => start.trim().parse::<u64>().map_err(to_service_error)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => 0,
+            Err(e) => return Err(to_service_error(e)),
+        };
+        if base_offsets.is_empty() {
+            base_offsets.push(persisted_start);
+        }
+        let segments = base_offsets
+            .into_iter()
+            .map(|base_offset| Segment::open(dir, base_offset))
+            .collect::<Result<Vec<_>, _>>()?;
+        let start_offset = persisted_start.max(segments[0].base_offset);
+        Ok(TopicLog {
+            dir: dir.to_path_buf(),
+            segments,
+            start_offset,
+            segment_size,
+        })
+    }
+
+    pub fn start_offset(&self) -> u64 {
+        self.start_offset
+    }
+
+    pub fn next_offset(&self) -> u64 {
+        self.active().end_offset()
+    }
+
+    fn active(&self) -> &Segment {
+        self.segments
+            .last()
+            .expect("a log has at least one segment")
+    }
+
+    pub fn append(&mut self, record: &[u8]) -> Result<u64, ExchangeError> {
+        if self.active().size >= self.segment_size {
+            // only the active segment is synced afterwards
+            self.sync()?;
+            let segment = Segment::open(&self.dir, self.next_offset())?;
+            self.segments.push(segment);
+        }
+        let offset = self.next_offset();
+        self.segments
+            .last_mut()
+            .expect("a log has at least one segment")
+            .append(record)?;
+        Ok(offset)
+    }
+
+    fn locate(&self, offset: u64) -> Option<(&Segment, usize)> {
+        if offset < self.start_offset || offset >= self.next_offset() {
+            return None;
+        }
+        let position = self
+            .segments
+            .partition_point(|segment| segment.base_offset <= offset);
+        let segment = &self.segments[position.checked_sub(1)?];
+        Some((segment, (offset - segment.base_offset) as usize))
+    }
+
+    /// The record at that offset, if still retained.
+    pub fn read(&self, offset: u64) -> Result<Option<Vec<u8>>, ExchangeError> {
+        match self.locate(offset) {
+            Some((segment, index)) => segment.read(index).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn record_size(&self, offset: u64) -> u64 {
+        self.locate(offset)
+            .map(|(segment, index)| segment.record_size(index))
+            .unwrap_or(0)
+    }
+
+    /// First retained offset whose record satisfies the predicate, or the
+    /// next offset if none does. The records are scanned in order, as
+    /// nothing tells they are sorted.
+    pub fn position(&self, mut pred: impl FnMut(&[u8]) -> bool) -> Result<u64, ExchangeError> {
+        for offset in self.start_offset..self.next_offset() {
+            if pred(&self.read(offset)?.unwrap_or_default()) {
+                return Ok(offset);
+            }
+        }
+        Ok(self.next_offset())
+    }
+
+    /// Total size of the retained records.
+    pub fn size(&self) -> u64 {
+        self.segments
+            .iter()
+            .map(|segment| {
+                let first = self.start_offset.saturating_sub(segment.base_offset) as usize;
+                (first.min(segment.positions.len())..segment.positions.len())
+                    .map(|index| segment.record_size(index))
+                    .sum::<u64>()
+            })
+            .sum()
+    }
+
+    /// Stop retaining the records before that offset, deleting the segments
+    /// that only hold such records. The active segment is always kept.
+    pub fn truncate_before(&mut self, offset: u64) -> Result<(), ExchangeError> {
+        let offset = offset.min(self.next_offset());
+        if offset <= self.start_offset {
+            return Ok(());
+        }
+        self.start_offset = offset;
+        std::fs::write(self.dir.join(START_OFFSET_FILE), offset.to_string())
+            .map_err(to_service_error)?;
+        while self.segments.len() > 1 && self.segments[0].end_offset() <= offset {
+            let segment = self.segments.remove(0);
+            tracing::debug!("delete segment {:?}", segment.path);
+            std::fs::remove_file(&segment.path).map_err(to_service_error)?;
+        }
+        Ok(())
+    }
+
+    /// Flush the active segment to disk, the previous ones being flushed
+    /// when rolled over.
+    pub fn sync(&mut self) -> Result<(), ExchangeError> {
+        self.active().file.sync_data().map_err(to_service_error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode_name, encode_name, LogKey, TopicLog};
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn encode_names() {
+        for name in ["public", "ARTCODED", "my_tenant", "ten/ant.é", "..", "a/b"] {
+            let encoded = encode_name(name);
+            assert!(!encoded.contains(['/', '.']));
+            assert_eq!(Some(name.to_string()), decode_name(&encoded));
+        }
+        assert_eq!("_2e_2e/A_2fB", LogKey::new("..", "a/b").path());
+    }
+
+    #[test]
+    fn append_read_truncate() {
+        let dir = TempDir::new("segment_log");
+        let mut log = TopicLog::open(&dir, 20).unwrap();
+        for i in 0..10u8 {
+            assert_eq!(i as u64, log.append(&[i; 8]).unwrap());
+        }
+        // 12 bytes per record, so a new segment every two records
+        assert_eq!(5, log.segments.len());
+        assert_eq!(Some(vec![3; 8]), log.read(3).unwrap());
+        assert_eq!(80, log.size());
+
+        log.truncate_before(5).unwrap();
+        assert_eq!(None, log.read(4).unwrap());
+        assert_eq!(Some(vec![5; 8]), log.read(5).unwrap());
+        assert_eq!(7, log.position(|record| record[0] >= 7).unwrap());
+        assert_eq!(5, log.position(|_| true).unwrap());
+        assert_eq!(10, log.position(|_| false).unwrap());
+        assert_eq!(3, log.segments.len());
+        assert_eq!(40, log.size());
+
+        let log = TopicLog::open(&dir, 20).unwrap();
+        assert_eq!(5, log.start_offset());
+        assert_eq!(10, log.next_offset());
+        assert_eq!(Some(vec![9; 8]), log.read(9).unwrap());
+    }
+}
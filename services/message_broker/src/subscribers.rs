@@ -0,0 +1,309 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fmt::Display,
+    hash::{Hash, Hasher},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+};
+
+use axum::extract::ws::Message;
+use futures_util::{Sink, SinkExt};
+use mu_rust_message_common::TextMessage;
+use tokio::sync::{
+    mpsc::{self, error::TrySendError},
+    Notify,
+};
+
+use crate::exchange_manager::ExchangeError;
+
+const SHARDS: usize = 16;
+
+/// Unique id the broker gives each websocket connection.
+pub type ConnectionId = u64;
+
+/// What to do when a service connects while already connected, set with
+/// `PUB_DUPLICATE_SERVICE` to `reject` (the default) or `take-over`. Taking
+/// over suits a single replica per service id: two replicas that reconnect
+/// would keep taking each other over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// Refuse the new connection.
+    Reject,
+    /// Close the previous connection, the new one resuming its deliveries.
+    TakeOver,
+}
+
+impl FromStr for DuplicatePolicy {
+    type Err = ExchangeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(DuplicatePolicy::Reject),
+            "take-over" => Ok(DuplicatePolicy::TakeOver),
+            _ => Err(ExchangeError {
+                msg: format!("unknown duplicate service policy {s}"),
+            }),
+        }
+    }
+}
+
+/// Frames waiting to be written to the websocket of a subscriber. A task per
+/// subscriber drains the bounded buffer, so that a slow consumer only delays
+/// itself. The socket is closed once every handle is dropped.
+#[derive(Debug, Clone)]
+pub struct Outbound {
+    service_id: String,
+    sender: mpsc::Sender<Message>,
+    /// Set when a frame did not fit, until the task makes room.
+    full: Arc<AtomicBool>,
+}
+
+impl Outbound {
+    /// Spawn the task writing the frames to the sink. Once a full buffer has
+    /// room again, `on_drain` is notified.
+    pub fn spawn<S>(
+        service_id: &str,
+        mut sink: S,
+        capacity: usize,
+        on_drain: Arc<Notify>,
+    ) -> Outbound
+    where
+        S: Sink<Message> + Unpin + Send + 'static,
+        S::Error: Display,
+    {
+        let (sender, mut receiver) = mpsc::channel::<Message>(capacity);
+        let full = Arc::new(AtomicBool::new(false));
+        let outbound = Outbound {
+            service_id: service_id.to_owned(),
+            sender,
+            full: full.clone(),
+        };
+        let service_id = service_id.to_owned();
+        tokio::spawn(async move {
+            while let Some(message) = receiver.recv().await {
+                if full.swap(false, Ordering::AcqRel) {
+                    on_drain.notify_one();
+                }
+                if let Err(e) = sink.send(message).await {
+                    tracing::error!("error {e} for subscriber {service_id}");
+                    return;
+                }
+            }
+            if let Err(e) = sink.close().await {
+                tracing::debug!("could not close the socket of {service_id}: {e}");
+            }
+        });
+        outbound
+    }
+
+    /// Queue the frame without waiting: it is given back when the buffer is
+    /// full or the socket is gone.
+    pub fn try_send(&self, message: Message) -> Result<(), TrySendError<Message>> {
+        match self.sender.try_send(message) {
+            Err(TrySendError::Full(message)) => {
+                self.full.store(true, Ordering::Release);
+                // the task may have made room before the flag was set
+                self.sender.try_send(message)
+            }
+            sent => sent,
+        }
+    }
+
+    /// Whether a frame did not fit and the buffer has had no room since.
+    pub fn is_full(&self) -> bool {
+        self.full.load(Ordering::Acquire)
+    }
+
+    /// Queue the frame, waiting for room in the buffer.
+    pub async fn send(&self, message: Message) -> Result<(), ExchangeError> {
+        self.sender.send(message).await.map_err(|_| ExchangeError {
+            msg: "subscriber disconnected".into(),
+        })
+    }
+
+    /// Send a control frame.
+    pub fn send_text(&self, message: &TextMessage) {
+        let frame = match message.serialize() {
+            Ok(frame) => frame,
+            Err(e) => {
+                tracing::error!("could not serialize frame {e}");
+                return;
+            }
+        };
+        if let Err(e) = self.try_send(Message::Text(frame)) {
+            tracing::error!("could not send frame to {}: {e}", self.service_id);
+        }
+    }
+
+    /// Report a rejected message back as an error frame.
+    pub fn send_error(&self, error: &ExchangeError) {
+        self.send_text(&TextMessage::Error(error.msg.clone()));
+    }
+}
+
+/// The current connection of a service.
+#[derive(Debug, Clone)]
+pub struct Connection {
+    pub id: ConnectionId,
+    pub outbound: Outbound,
+}
+
+/// Connected subscribers by service id, sharded so that looking one up does
+/// not contend with the exchange manager nor with the other shards.
+#[derive(Debug, Clone)]
+pub struct Subscribers {
+    shards: Arc<Vec<RwLock<HashMap<String, Connection>>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl Default for Subscribers {
+    fn default() -> Self {
+        Subscribers {
+            shards: Arc::new((0..SHARDS).map(|_| Default::default()).collect()),
+            next_id: Default::default(),
+        }
+    }
+}
+
+impl Subscribers {
+    fn shard(&self, service_id: &str) -> &RwLock<HashMap<String, Connection>> {
+        let mut hasher = DefaultHasher::new();
+        service_id.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARDS]
+    }
+
+    pub fn next_id(&self) -> ConnectionId {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Register the connection of the service, returning the one it replaces.
+    pub fn insert(&self, service_id: &str, connection: Connection) -> Option<Connection> {
+        self.shard(service_id)
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(service_id.to_owned(), connection)
+    }
+
+    /// Remove the connection of the service, only if it is that one when an
+    /// id is given.
+    pub fn remove(&self, service_id: &str, id: Option<ConnectionId>) -> Option<Connection> {
+        let mut shard = self
+            .shard(service_id)
+            .write()
+            .unwrap_or_else(|e| e.into_inner());
+        match (shard.get(service_id), id) {
+            (Some(connection), Some(id)) if connection.id != id => None,
+            _ => shard.remove(service_id),
+        }
+    }
+
+    /// Outbound frames of the current connection of the service.
+    pub fn get(&self, service_id: &str) -> Option<Outbound> {
+        self.shard(service_id)
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(service_id)
+            .map(|connection| connection.outbound.clone())
+    }
+
+    /// Outbound frames of that connection, if still the one of the service.
+    pub fn connection(&self, service_id: &str, id: ConnectionId) -> Option<Outbound> {
+        self.shard(service_id)
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(service_id)
+            .filter(|connection| connection.id == id)
+            .map(|connection| connection.outbound.clone())
+    }
+
+    pub fn contains(&self, service_id: &str) -> bool {
+        self.shard(service_id)
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .contains_key(service_id)
+    }
+
+    pub fn service_ids(&self) -> Vec<String> {
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .read()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .keys()
+                    .cloned()
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.read().unwrap_or_else(|e| e.into_inner()).len())
+            .sum()
+    }
+
+    /// Answer a ping on that connection.
+    pub fn pong(&self, service_id: &str, id: ConnectionId) {
+        if let Some(outbound) = self.connection(service_id, id) {
+            if let Err(e) = outbound.try_send(Message::Pong("Pong!".into())) {
+                tracing::warn!("could not send pong message to {service_id}: {e}");
+            }
+        }
+    }
+
+    /// Send a control frame on that connection.
+    pub fn send_text(&self, service_id: &str, id: ConnectionId, message: &TextMessage) {
+        if let Some(outbound) = self.connection(service_id, id) {
+            outbound.send_text(message);
+        }
+    }
+
+    /// Report a rejected message back on that connection as an error frame.
+    pub fn send_error(&self, service_id: &str, id: ConnectionId, error: &ExchangeError) {
+        if let Some(outbound) = self.connection(service_id, id) {
+            outbound.send_error(error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use axum::extract::ws::Message;
+    use futures_util::sink::drain;
+
+    use super::{Connection, Outbound, Subscribers};
+
+    #[tokio::test]
+    async fn route_to_subscribers() {
+        let subscribers = Subscribers::default();
+        for service_id in ["Nordine", "Marie", "Sophie"] {
+            let connection = Connection {
+                id: subscribers.next_id(),
+                outbound: Outbound::spawn(service_id, drain(), 1, Default::default()),
+            };
+            assert!(subscribers.insert(service_id, connection).is_none());
+        }
+        assert_eq!(3, subscribers.len());
+        let outbound = subscribers.get("Marie").unwrap();
+        assert!(outbound.try_send(Message::Text("hello".into())).is_ok());
+
+        let taken_over = Connection {
+            id: subscribers.next_id(),
+            outbound: Outbound::spawn("Marie", drain(), 1, Default::default()),
+        };
+        let previous = subscribers.insert("Marie", taken_over).unwrap();
+        assert_eq!(1, previous.id);
+        assert!(subscribers.connection("Marie", previous.id).is_none());
+        assert!(subscribers.remove("Marie", Some(previous.id)).is_none());
+        assert!(subscribers.remove("Marie", Some(3)).is_some());
+        assert!(!subscribers.contains("Marie"));
+        let mut service_ids = subscribers.service_ids();
+        service_ids.sort();
+        assert_eq!(vec!["Nordine", "Sophie"], service_ids);
+    }
+}
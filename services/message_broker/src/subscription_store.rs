@@ -0,0 +1,303 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use mu_rust_message_common::{topic::TopicPattern, Backpressure};
+use serde::{Deserialize, Serialize};
+
+use crate::exchange_manager::{to_service_error, ExchangeError};
+
+/// Who consumes a subscription: a single service, or a consumer group whose
+/// members share the deliveries.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ConsumerId {
+    Service(String),
+    Group(String),
+}
+
+impl Display for ConsumerId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConsumerId::Service(service_id) => write!(f, "{service_id}"),
+            ConsumerId::Group(group) => write!(f, "group:{group}"),
+        }
+    }
+}
+
+/// A topic pattern scoped to the tenant it receives exchanges from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TenantTopic {
+    pub tenant: String,
+    pub topic: TopicPattern,
+}
+
+/// Delivery cursor of a consumer in a topic log: every offset below
+/// `committed` is settled, plus the offsets acknowledged out of order.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cursor {
+    pub committed: u64,
+    #[serde(default)]
+    pub acked: BTreeSet<u64>,
+}
+
+impl Cursor {
+    pub fn is_acked(&self, offset: u64) -> bool {
+        offset < self.committed || self.acked.contains(&offset)
+    }
+
+    /// Returns false if the offset was already settled.
+    pub fn ack(&mut self, offset: u64) -> bool {
+        if self.is_acked(offset) {
+            return false;
+        }
+        self.acked.insert(offset);
+        while self.acked.remove(&self.committed) {
+            self.committed += 1;
+        }
+        true
+    }
+
+    /// Offsets before the end offset not settled yet.
+    pub fn pending(&self, end_offset: u64) -> u64 {
+        let acked = self.acked.range(..end_offset).count() as u64;
+        end_offset
+            .saturating_sub(self.committed)
+            .saturating_sub(acked)
+    }
+
+    /// Restart from that offset, forgetting what was settled after it.
+    pub fn seek(&mut self, offset: u64) {
+        self.committed = offset;
+        self.acked.clear();
+    }
+
+    /// Skip the offsets that are no longer retained.
+    pub fn skip_to(&mut self, start_offset: u64) -> bool {
+        if self.committed >= start_offset {
+            return false;
+        }
+        self.committed = start_offset;
+        self.acked = self.acked.split_off(&start_offset);
+        while self.acked.remove(&self.committed) {
+            self.committed += 1;
+        }
+        true
+    }
+}
+
+/// Subscriptions of a consumer, kept across reconnects and broker restarts.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct DurableSubscription {
+    pub topics: Vec<TenantTopic>,
+    /// Delivery cursors, keyed by topic log path.
+    #[serde(default)]
+    pub cursors: HashMap<String, Cursor>,
+    /// Service ids of the group members, empty for a service consumer.
+    #[serde(default)]
+    pub members: Vec<String>,
+    #[serde(default)]
+    pub backpressure: Option<Backpressure>,
+}
+
+#[derive(Debug)]
+pub struct SubscriptionStore {
+    path: PathBuf,
+    subscriptions: HashMap<ConsumerId, DurableSubscription>,
+    dirty: bool,
+}
+
+impl SubscriptionStore {
+    pub fn open(path: &Path) -> Result<SubscriptionStore, ExchangeError> {
+        let subscriptions = if path.exists() {
+            let content = std::fs::read(path).map_err(to_service_error)?;
+            serde_json::from_slice::<Vec<(ConsumerId, DurableSubscription)>>(&content)
+                .map_err(to_service_error)?
+                .into_iter()
+                .collect()
+        } else {
+            HashMap::new()
+        };
+        Ok(SubscriptionStore {
+            path: path.to_path_buf(),
+            subscriptions,
+            dirty: false,
+        })
+    }
+
+    /// Returns false if the consumer was already subscribed to that topic.
+    pub fn subscribe(&mut self, consumer: &ConsumerId, topic: &TenantTopic) -> bool {
+        let subscription = self.subscriptions.entry(consumer.clone()).or_default();
+        if subscription.topics.contains(topic) {
+            return false;
+        }
+        subscription.topics.push(topic.clone());
+        self.dirty = true;
+        true
+    }
+
+    /// Returns false if the consumer was not subscribed to that topic. A
+    /// consumer left without topic nor member is forgotten.
+    pub fn unsubscribe(&mut self, consumer: &ConsumerId, topic: &TenantTopic) -> bool {
+        let Some(subscription) = self.subscriptions.get_mut(consumer) else {
+            return false;
+        };
+        let Some(position) = subscription.topics.iter().position(|t| t == topic) else {
+            return false;
+        };
+        subscription.topics.remove(position);
+        if subscription.topics.is_empty() && subscription.members.is_empty() {
+            self.subscriptions.remove(consumer);
+        }
+        self.dirty = true;
+        true
+    }
+
+    /// Returns the members left in the group.
+    pub fn leave_group(&mut self, group: &str, service_id: &str) -> usize {
+        let Some(subscription) = self
+            .subscriptions
+            .get_mut(&ConsumerId::Group(group.to_owned()))
+        else {
+            return 0;
+        };
+        if let Some(position) = subscription.members.iter().position(|m| m == service_id) {
+            subscription.members.remove(position);
+            self.dirty = true;
+        }
+        subscription.members.len()
+    }
+
+    pub fn join_group(&mut self, group: &str, service_id: &str) {
+        let subscription = self
+            .subscriptions
+            .entry(ConsumerId::Group(group.to_owned()))
+            .or_default();
+        if !subscription.members.iter().any(|m| m == service_id) {
+            subscription.members.push(service_id.to_owned());
+            self.dirty = true;
+        }
+    }
+
+    pub fn set_backpressure(&mut self, consumer: &ConsumerId, backpressure: Backpressure) {
+        let subscription = self.subscriptions.entry(consumer.clone()).or_default();
+        if subscription.backpressure != Some(backpressure) {
+            subscription.backpressure = Some(backpressure);
+            self.dirty = true;
+        }
+    }
+
+    pub fn get(&self, consumer: &ConsumerId) -> Option<&DurableSubscription> {
+        self.subscriptions.get(consumer)
+    }
+
+    /// Cursor of the consumer in a log, starting at the first retained offset
+    /// when the consumer never read from it.
+    pub fn cursor(&self, consumer: &ConsumerId, log: &str, start_offset: u64) -> Cursor {
+        let mut cursor = self
+            .subscriptions
+            .get(consumer)
+            .and_then(|s| s.cursors.get(log))
+            .cloned()
+            .unwrap_or_default();
+        cursor.skip_to(start_offset);
+        cursor
+    }
+
+    pub fn ack(&mut self, consumer: &ConsumerId, log: &str, offset: u64, start_offset: u64) {
+        if let Some(subscription) = self.subscriptions.get_mut(consumer) {
+            let cursor = subscription.cursors.entry(log.to_owned()).or_default();
+            let skipped = cursor.skip_to(start_offset);
+            self.dirty |= cursor.ack(offset) || skipped;
+        }
+    }
+
+    pub fn seek(&mut self, consumer: &ConsumerId, log: &str, offset: u64) {
+        if let Some(subscription) = self.subscriptions.get_mut(consumer) {
+            subscription
+                .cursors
+                .entry(log.to_owned())
+                .or_default()
+                .seek(offset);
+            self.dirty = true;
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&ConsumerId, &DurableSubscription)> {
+        self.subscriptions.iter()
+    }
+
+    pub fn save(&mut self) -> Result<(), ExchangeError> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let content = serde_json::to_vec(&self.subscriptions.iter().collect::<Vec<_>>())
+            .map_err(to_service_error)?;
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, content).map_err(to_service_error)?;
+        std::fs::rename(&tmp, &self.path).map_err(to_service_error)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mu_rust_message_common::topic::TopicPattern;
+
+    use super::{ConsumerId, Cursor, SubscriptionStore, TenantTopic};
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn persist_subscriptions_and_cursor() {
+        let dir = TempDir::new("subscription_store");
+        let path = dir.join("subscriptions.json");
+
+        let mut store = SubscriptionStore::open(&path).unwrap();
+        let consumer = ConsumerId::Service("Nordine".into());
+        let topic = TenantTopic {
+            tenant: "public".into(),
+            topic: TopicPattern::parse("Animal").unwrap(),
+        };
+        assert!(store.subscribe(&consumer, &topic));
+        assert!(!store.subscribe(&consumer, &topic));
+        assert!(store.unsubscribe(&consumer, &topic));
+        assert!(!store.unsubscribe(&consumer, &topic));
+        assert!(store.get(&consumer).is_none());
+        assert!(store.subscribe(&consumer, &topic));
+        store.ack(&consumer, "public/ANIMAL", 4, 3);
+        store.ack(&consumer, "public/ANIMAL", 6, 3);
+        store.save().unwrap();
+
+        let store = SubscriptionStore::open(&path).unwrap();
+        let (persisted, subscription) = store.iter().next().unwrap();
+        assert_eq!(&consumer, persisted);
+        assert_eq!(vec![topic], subscription.topics);
+        let cursor = store.cursor(&consumer, "public/ANIMAL", 0);
+        assert!(!cursor.is_acked(3));
+        assert!(cursor.is_acked(4));
+        assert!(!cursor.is_acked(5));
+        assert!(cursor.is_acked(6));
+        assert_eq!(3, store.cursor(&consumer, "other", 3).committed);
+    }
+
+    #[test]
+    fn advance_cursor() {
+        let mut cursor = Cursor::default();
+        assert!(cursor.ack(1));
+        assert_eq!(0, cursor.committed);
+        assert!(cursor.ack(0));
+        assert_eq!(2, cursor.committed);
+        assert!(cursor.acked.is_empty());
+        assert!(!cursor.ack(1));
+        assert!(cursor.ack(3));
+        assert_eq!(3, cursor.pending(6));
+
+        cursor.ack(5);
+        cursor.ack(8);
+        assert!(cursor.skip_to(5));
+        assert_eq!(6, cursor.committed);
+        assert!(cursor.is_acked(8));
+    }
+}
@@ -0,0 +1,155 @@
+use std::{env::var, fs::File, io::BufReader, net::SocketAddr, sync::Arc};
+
+use axum::{Extension, Router};
+use hyper::server::conn::Http;
+use rustls::{
+    server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, NoClientAuth},
+    Certificate, PrivateKey, RootCertStore, ServerConfig,
+};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+use crate::{
+    constants::{PUB_TLS_CERT, PUB_TLS_CLIENT_AUTH_REQUIRED, PUB_TLS_CLIENT_CA, PUB_TLS_KEY},
+    exchange_manager::{to_service_error, ExchangeError},
+};
+
+/// Service id of a connection authenticated with a client certificate.
+#[derive(Debug, Clone)]
+pub struct ClientCertificate {
+    pub service_id: String,
+}
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>, ExchangeError> {
+    let mut reader = BufReader::new(File::open(path).map_err(to_service_error)?);
+    let certs = rustls_pemfile::certs(&mut reader).map_err(to_service_error)?;
+    if certs.is_empty() {
+        return Err(ExchangeError {
+            msg: format!("no certificate in {path}"),
+        });
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &str) -> Result<PrivateKey, ExchangeError> {
+    let mut reader = BufReader::new(File::open(path).map_err(to_service_error)?);
+    rustls_pemfile::read_all(&mut reader)
+        .map_err(to_service_error)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| ExchangeError {
+            msg: format!("no private key in {path}"),
+        })
+}
+
+/// TLS configuration from `PUB_TLS_CERT` and `PUB_TLS_KEY`, `None` to serve
+/// plain HTTP. With `PUB_TLS_CLIENT_CA`, client certificates signed by that
+/// CA are verified, and required if `PUB_TLS_CLIENT_AUTH_REQUIRED` is true.
+pub fn load_config() -> Result<Option<Arc<ServerConfig>>, ExchangeError> {
+    let (Ok(cert), Ok(key)) = (var(PUB_TLS_CERT), var(PUB_TLS_KEY)) else {
+        return Ok(None);
+    };
+    let client_auth = match var(PUB_TLS_CLIENT_CA) {
+        Ok(ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(&ca)? {
+                roots.add(&cert).map_err(to_service_error)?;
+            }
+            let required = var(PUB_TLS_CLIENT_AUTH_REQUIRED)
+                .unwrap_or_else(|_| String::from("false"))
+                .parse::<bool>()
+                .map_err(to_service_error)?;
+            if required {
+                AllowAnyAuthenticatedClient::new(roots).boxed()
+            } else {
+                AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed()
+            }
+        }
+        Err(_) => NoClientAuth::boxed(),
+    };
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(client_auth)
+        .with_single_cert(load_certs(&cert)?, load_key(&key)?)
+        .map_err(to_service_error)?;
+    Ok(Some(Arc::new(config)))
+}
+
+/// The service id a client certificate stands for: its subject common name,
+/// or else its first DNS subject alternative name.
+pub fn service_id_of(cert: &Certificate) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(&cert.0).ok()?;
+    let common_name = cert
+        .subject()
+        .iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+        .map(String::from);
+    common_name.or_else(|| {
+        cert.subject_alternative_name()
+            .ok()
+            .flatten()?
+            .value
+            .general_names
+            .iter()
+            .find_map(|name| match name {
+                GeneralName::DNSName(dns) => Some(dns.to_string()),
+                _ => None,
+            })
+    })
+}
+
+/// Serve the app over TLS, exposing the verified client certificate, if
+/// any, as a `ClientCertificate` extension.
+pub async fn serve(
+    addr: SocketAddr,
+    app: Router,
+    config: Arc<ServerConfig>,
+) -> Result<(), ExchangeError> {
+    let listener = TcpListener::bind(addr).await.map_err(to_service_error)?;
+    let acceptor = TlsAcceptor::from(config);
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                tracing::error!("could not accept connection: {e}");
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let app = app.clone();
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    tracing::debug!("tls handshake with {peer} failed: {e}");
+                    return;
+                }
+            };
+            let client_certificate = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(service_id_of)
+                .map(|service_id| ClientCertificate { service_id });
+            let app = match client_certificate {
+                Some(client_certificate) => app.layer(Extension(client_certificate)),
+                None => app,
+            };
+            if let Err(e) = Http::new()
+                .serve_connection(stream, app)
+                .with_upgrades()
+                .await
+            {
+                tracing::debug!("connection with {peer} failed: {e}");
+            }
+        });
+    }
+}
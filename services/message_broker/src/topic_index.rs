@@ -0,0 +1,137 @@
+use std::collections::{HashMap, HashSet};
+
+use mu_rust_message_common::topic::{TopicPattern, TopicSegment, TOPIC_SEPARATOR};
+
+use crate::subscription_store::ConsumerId;
+
+/// Trie of subscription patterns, one level per node, so that matching a
+/// published topic only walks the levels of that topic.
+#[derive(Debug, Default)]
+pub struct TopicIndex {
+    root: Node,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    literals: HashMap<String, Node>,
+    single: Option<Box<Node>>,
+    multi: Option<Box<Node>>,
+    consumers: HashSet<ConsumerId>,
+}
+
+impl Node {
+    fn is_empty(&self) -> bool {
+        self.consumers.is_empty()
+            && self.literals.is_empty()
+            && self.single.is_none()
+            && self.multi.is_none()
+    }
+}
+
+impl TopicIndex {
+    pub fn insert(&mut self, pattern: &TopicPattern, consumer: &ConsumerId) {
+        let mut node = &mut self.root;
+        for segment in pattern.segments() {
+            node = match segment {
+                TopicSegment::Literal(literal) => node.literals.entry(literal.clone()).or_default(),
+                TopicSegment::Single => node.single.get_or_insert_with(Default::default),
+                TopicSegment::Multi => node.multi.get_or_insert_with(Default::default),
+            };
+        }
+        node.consumers.insert(consumer.clone());
+    }
+
+    pub fn remove(&mut self, pattern: &TopicPattern, consumer: &ConsumerId) {
+        fn remove(node: &mut Node, segments: &[TopicSegment], consumer: &ConsumerId) {
+            let Some((segment, rest)) = segments.split_first() else {
+                node.consumers.remove(consumer);
+                return;
+            };
+            match segment {
+                TopicSegment::Literal(literal) => {
+                    if let Some(child) = node.literals.get_mut(literal) {
+                        remove(child, rest, consumer);
+                        if child.is_empty() {
+                            node.literals.remove(literal);
+                        }
+                    }
+                }
+                TopicSegment::Single => {
+                    if let Some(child) = node.single.as_mut() {
+                        remove(child, rest, consumer);
+                        if child.is_empty() {
+                            node.single = None;
+                        }
+                    }
+                }
+                TopicSegment::Multi => {
+                    if let Some(child) = node.multi.as_mut() {
+                        remove(child, rest, consumer);
+                        if child.is_empty() {
+                            node.multi = None;
+                        }
+                    }
+                }
+            }
+        }
+        remove(&mut self.root, pattern.segments(), consumer);
+    }
+
+    /// Consumers having at least one pattern matching the topic.
+    pub fn matches(&self, topic: &str) -> HashSet<ConsumerId> {
+        fn collect(node: &Node, levels: &[&str], out: &mut HashSet<ConsumerId>) {
+            if let Some(multi) = &node.multi {
+                for skip in 0..=levels.len() {
+                    collect(multi, &levels[skip..], out);
+                }
+            }
+            let Some((level, rest)) = levels.split_first() else {
+                out.extend(node.consumers.iter().cloned());
+                return;
+            };
+            if let Some(child) = node.literals.get(*level) {
+                collect(child, rest, out);
+            }
+            if let Some(single) = &node.single {
+                collect(single, rest, out);
+            }
+        }
+        let topic = topic.to_uppercase();
+        let levels = topic.split(TOPIC_SEPARATOR).collect::<Vec<_>>();
+        let mut consumers = HashSet::new();
+        collect(&self.root, &levels, &mut consumers);
+        consumers
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mu_rust_message_common::topic::TopicPattern;
+
+    use super::TopicIndex;
+    use crate::subscription_store::ConsumerId;
+
+    #[test]
+    fn match_index() {
+        let mut index = TopicIndex::default();
+        let exact = TopicPattern::parse("delta.created").unwrap();
+        let single = TopicPattern::parse("delta.*").unwrap();
+        let multi = TopicPattern::parse("#.created").unwrap();
+        index.insert(&exact, &ConsumerId::Service("exact".into()));
+        index.insert(&single, &ConsumerId::Service("single".into()));
+        index.insert(&multi, &ConsumerId::Group("multi".into()));
+
+        let matched = index.matches("Delta.Created");
+        assert_eq!(3, matched.len());
+        assert!(matched.contains(&ConsumerId::Group("multi".into())));
+        assert_eq!(1, index.matches("delta.deleted").len());
+        assert_eq!(1, index.matches("person.address.created").len());
+        assert!(index.matches("person.deleted").is_empty());
+
+        index.remove(&single, &ConsumerId::Service("single".into()));
+        assert_eq!(2, index.matches("delta.created").len());
+        index.remove(&exact, &ConsumerId::Service("exact".into()));
+        index.remove(&multi, &ConsumerId::Group("multi".into()));
+        assert!(index.root.is_empty());
+    }
+}